@@ -0,0 +1,38 @@
+use std::env;
+use std::process::Command;
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned())
+}
+
+fn main() {
+    let git_commit = command_output("git", &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc_version =
+        command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_owned());
+
+    let mut features: Vec<_> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    let features = if features.is_empty() {
+        "none".to_owned()
+    } else {
+        features.join(", ")
+    };
+
+    println!("cargo:rustc-env=SENKLOT_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=SENKLOT_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=SENKLOT_FEATURES={}", features);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
@@ -0,0 +1,14 @@
+use anyhow::Result;
+
+/// A way of enforcing locks on domains. `State` decides which domains should
+/// be locked and hands each decision to a blocker, which applies them all on
+/// `commit`.
+pub trait Blocker {
+    fn set_locked(&mut self, domain: &str, locked: bool);
+    fn commit(&mut self) -> Result<()>;
+    fn is_locked(&self, domain: &str) -> bool;
+    /// Undoes everything the blocker applied, when another backend takes over.
+    fn remove(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
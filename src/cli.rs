@@ -1,16 +1,143 @@
 use anyhow::Result;
+use std::path::PathBuf;
 use structopt::clap::AppSettings::*;
 use structopt::clap::ErrorKind::*;
 use structopt::StructOpt;
 
+#[derive(StructOpt)]
+pub struct Cli {
+    /// Use this hosts file instead of the configured one
+    #[structopt(long, parse(from_os_str))]
+    pub hosts_file: Option<PathBuf>,
+    /// Use this state file instead of the configured one
+    #[structopt(long, parse(from_os_str))]
+    pub state_file: Option<PathBuf>,
+    /// Use this socket instead of the configured one
+    #[structopt(long, parse(from_os_str))]
+    pub socket: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Args,
+}
+
 #[derive(StructOpt)]
 pub enum Args {
-    Start {},
-    Unlock { name: String },
+    Start(StartOptions),
+    Unlock(UnlockOptions),
+    Lock(LockOptions),
+    Remaining { name: String },
+    Why { name: String },
+    Schedule {},
+    FastTick { duration: String },
+    Version {},
+    TailLog {},
+    Health(HealthOptions),
+    ExportHosts(ExportHostsOptions),
+    Focus(FocusOptions),
+    Stats {},
+    DiffConfig {},
+    TestHook(TestHookOptions),
+    Status {},
+    Simulate(SimulateOptions),
+    HostsMap {},
+    FormatHosts {},
+    Check {},
+    Validate(ValidateOptions),
+}
+
+#[derive(StructOpt)]
+pub struct StartOptions {
+    /// Do not watch /etc/hosts, only reconcile it every interval
+    #[structopt(long)]
+    pub no_watch: bool,
+    /// Unlock every managed domain when the daemon exits
+    #[structopt(long)]
+    pub unlock_on_exit: bool,
+    /// Stay attached to the terminal instead of daemonizing
+    #[structopt(long)]
+    pub foreground: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ValidateOptions {
+    /// Config file to check instead of /etc/senklot/config
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct HealthOptions {
+    /// Seconds to wait for the daemon to answer
+    #[structopt(long, default_value = "5")]
+    pub timeout: u64,
+}
+
+#[derive(StructOpt)]
+pub struct ExportHostsOptions {
+    /// Also include unlocked domains, commented out
+    #[structopt(long)]
+    pub all: bool,
+}
+
+#[derive(StructOpt)]
+pub struct FocusOptions {
+    pub duration: String,
+    /// Only lock entries with this tag
+    #[structopt(long)]
+    pub tag: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub struct TestHookOptions {
+    pub name: String,
+    /// Which transition's hook to run
+    #[structopt(long, possible_values = &["lock", "unlock"], default_value = "unlock")]
+    pub action: String,
+}
+
+#[derive(StructOpt)]
+pub struct SimulateOptions {
+    /// How far ahead to simulate
+    #[structopt(long, default_value = "24")]
+    pub hours: i64,
+}
+
+#[derive(StructOpt)]
+pub struct UnlockOptions {
+    #[structopt(flatten)]
+    pub targets: Targets,
+    /// Reason for unlocking, instead of being prompted
+    #[structopt(long)]
+    pub reason: Option<String>,
+    /// The daemon's current unlock code, instead of being prompted
+    #[structopt(long)]
+    pub code: Option<String>,
+    /// Lock again after this long (e.g. `10m`), whatever the schedule says
+    #[structopt(long = "for")]
+    pub duration: Option<String>,
+    /// Unlock every entry whose schedule currently allows it
+    #[structopt(long)]
+    pub all_scheduled: bool,
+}
+
+#[derive(StructOpt)]
+pub struct LockOptions {
+    #[structopt(flatten)]
+    pub targets: Targets,
+}
+
+#[derive(StructOpt)]
+pub struct Targets {
+    pub name: Option<String>,
+    /// Read newline-separated entry names from stdin
+    #[structopt(long)]
+    pub stdin: bool,
+    /// Select every entry with this tag
+    #[structopt(long)]
+    pub tag: Option<String>,
 }
 
-pub fn get_args() -> Result<Args> {
-    let matches = Args::clap()
+pub fn get_args() -> Result<Cli> {
+    let matches = Cli::clap()
         .help_message("Print help message")
         .version_message("Print version message")
         .version_short("v")
@@ -29,5 +156,5 @@ pub fn get_args() -> Result<Args> {
             e
         })?;
 
-    Ok(Args::from_clap(&matches))
+    Ok(Cli::from_clap(&matches))
 }
@@ -0,0 +1,72 @@
+use chrono::offset::Local;
+use chrono::Duration;
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::config::LocalTime;
+
+pub trait Clock {
+    fn now(&self) -> LocalTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> LocalTime {
+        Local::now()
+    }
+}
+
+/// A clock that only moves when advanced, for simulating schedules.
+pub struct FixedClock {
+    now: Cell<LocalTime>,
+}
+
+impl FixedClock {
+    pub fn new(now: LocalTime) -> FixedClock {
+        FixedClock {
+            now: Cell::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> LocalTime {
+        self.now.get()
+    }
+}
+
+/// Lets a test keep advancing a clock it has handed over.
+impl<C: Clock> Clock for Rc<C> {
+    fn now(&self) -> LocalTime {
+        (**self).now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn fixed_clock_moves_only_when_advanced() {
+        let start = Local.ymd(2020, 6, 1).and_hms(12, 0, 0);
+        let clock = FixedClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::minutes(90));
+        assert_eq!(clock.now(), Local.ymd(2020, 6, 1).and_hms(13, 30, 0));
+    }
+
+    #[test]
+    fn system_clock_tells_the_current_time() {
+        let before = Local::now();
+        let now = SystemClock.now();
+        assert!(before <= now && now <= Local::now());
+    }
+}
@@ -1,30 +1,82 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use chrono::offset::Local;
-use chrono::{DateTime, Duration, NaiveTime as Time};
-use nom::character::complete::{digit0, digit1};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime as Time, Timelike, Weekday};
+use nom::character::complete::{digit0, digit1, space1};
 use nom::combinator::all_consuming;
-use nom::{alt, map, map_res, named, opt, recognize, tag, take, tuple};
-use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use nom::{
+    alt, complete, fold_many1, map, map_res, named, opt, recognize, separated_nonempty_list, tag,
+    take, tuple,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::paths::*;
+
 pub type LocalTime = DateTime<Local>;
 
 pub struct StaticDuration {
     pub begin: Time,
     pub end: Time,
+    /// The days the window applies on, every day if `None`.
+    pub weekdays: Option<Vec<Weekday>>,
 }
 
 impl StaticDuration {
     pub fn contains(&self, time: &LocalTime) -> bool {
         let t = time.time();
 
-        if self.begin < self.end {
-            self.begin <= t && t < self.end
+        // A window running past midnight belongs to the day it starts on, so
+        // `Fri 22:00-02:00` still holds at 01:00 on Saturday.
+        let day = if self.begin < self.end {
+            if t < self.begin || self.end <= t {
+                return false;
+            }
+            time.weekday()
+        } else if self.begin <= t {
+            time.weekday()
+        } else if t < self.end {
+            time.weekday().pred()
+        } else {
+            return false;
+        };
+
+        self.weekdays
+            .as_ref()
+            .is_none_or(|weekdays| weekdays.contains(&day))
+    }
+}
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+pub fn covers_whole_day(windows: &[StaticDuration]) -> bool {
+    let mut ranges = Vec::new();
+    for window in windows.iter().filter(|window| window.weekdays.is_none()) {
+        let begin = window.begin.num_seconds_from_midnight();
+        let end = window.end.num_seconds_from_midnight();
+        if begin < end {
+            ranges.push((begin, end));
         } else {
-            self.end <= t || t < self.begin
+            ranges.push((begin, SECS_PER_DAY));
+            ranges.push((0, end));
+        }
+    }
+    ranges.sort();
+
+    let mut covered = 0;
+    for (begin, end) in ranges {
+        if begin > covered {
+            return false;
         }
+        covered = covered.max(end);
     }
+
+    covered >= SECS_PER_DAY
 }
 
 impl<'a> Deserialize<'a> for StaticDuration {
@@ -34,14 +86,25 @@ impl<'a> Deserialize<'a> for StaticDuration {
     {
         use serde::de::Error;
         let string = Deserialize::deserialize(deserializer)?;
-        let (_, o) = all_consuming(static_duration)(string).map_err(Error::custom)?;
-        Ok(o)
+        parse_static_duration(string).map_err(Error::custom)
     }
 }
 
+fn parse_static_duration(string: &str) -> Result<StaticDuration> {
+    let (_, o) = all_consuming(static_duration)(string).map_err(|_| {
+        anyhow!(
+            "expected a window like `09:00-17:00` or `Sat,Sun 10:00-12:00`, got `{}`",
+            string
+        )
+    })?;
+    Ok(o)
+}
+
 pub enum DurationUnit {
+    Seconds,
     Minutes,
     Hours,
+    Days,
 }
 
 fn deserialize_hm<'a, D>(deserializer: D) -> Result<chrono::Duration, D::Error>
@@ -50,8 +113,98 @@ where
 {
     use serde::de::Error;
     let string = Deserialize::deserialize(deserializer)?;
-    let (_, o) = all_consuming(mh_duration)(string).map_err(Error::custom)?;
-    Ok(o.into())
+    parse_duration(string).map_err(Error::custom)
+}
+
+fn deserialize_opt_hm<'a, D>(deserializer: D) -> Result<Option<chrono::Duration>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    deserialize_hm(deserializer).map(Some)
+}
+
+pub fn parse_duration(string: &str) -> Result<Duration> {
+    if all_consuming(float)(string).is_ok() {
+        return Err(anyhow!(
+            "duration `{}` requires a unit (d/h/m/s), e.g. '1h30m'",
+            string
+        ));
+    }
+    let (_, o) = all_consuming(mh_duration)(string)
+        .map_err(|_| anyhow!("expected a duration like `2h` or `1h30m`, got `{}`", string))?;
+    Ok(o)
+}
+
+/// Entry fields parsed with `parse_duration`.
+const DURATION_FIELDS: &[&str] = &[
+    "period",
+    "cool_time",
+    "unlock_delay",
+    "nag_after",
+    "lock_grace",
+];
+
+/// Entries are flattened into `Config` and their restriction is untagged, so
+/// serde reports a mistake in one without its name, field or line. Checking
+/// each entry on its own recovers them; other errors are returned as they are.
+pub fn explain_parse_error(source: &str, error: toml::de::Error) -> anyhow::Error {
+    let table: toml::value::Table = match toml::from_str(source) {
+        Ok(table) => table,
+        Err(_) => return error.into(),
+    };
+
+    for (name, value) in &table {
+        let entry = match value.as_table() {
+            Some(entry) if name != "paths" => entry,
+            _ => continue,
+        };
+        if let Err(e) = check_entry(entry) {
+            let line = table_line(source, name)
+                .map(|line| format!(" (line {})", line))
+                .unwrap_or_default();
+            return anyhow!("error in entry `{}`{}: {}", name, line, e);
+        }
+    }
+
+    error.into()
+}
+
+fn check_entry(entry: &toml::value::Table) -> Result<()> {
+    for (field, value) in entry {
+        let result = match (field.as_str(), value) {
+            (field, toml::Value::String(string)) if DURATION_FIELDS.contains(&field) => {
+                parse_duration(string).map(drop)
+            }
+            ("unlock", toml::Value::Array(windows)) => windows
+                .iter()
+                .filter_map(|window| window.as_str())
+                .try_for_each(|window| parse_static_duration(window).map(drop)),
+            _ => Ok(()),
+        };
+        result.map_err(|e| anyhow!("field `{}`: {}", field, e))?;
+    }
+
+    toml::Value::Table(entry.clone())
+        .try_into::<Entry>()
+        .map(drop)
+        .map_err(|e| {
+            if e.to_string().contains("untagged enum Restriction") {
+                anyhow!(
+                    "expected either `unlock`, both `period` and `cool_time`, or `restriction = \"manual\"`"
+                )
+            } else {
+                e.into()
+            }
+        })
+}
+
+/// The line of the `[name]` header, counting from 1.
+fn table_line(source: &str, name: &str) -> Option<usize> {
+    let header = format!("[{}]", name);
+    source
+        .lines()
+        .position(|line| line.split('#').next().unwrap_or("").trim() == header)
+        .map(|i| i + 1)
 }
 
 #[derive(Deserialize)]
@@ -65,24 +218,427 @@ pub enum Restriction {
         period: chrono::Duration,
         #[serde(deserialize_with = "deserialize_hm")]
         cool_time: chrono::Duration,
+        #[serde(default)]
+        cooldown_from: CooldownFrom,
     },
+    Manual(ManualRestriction),
+}
+
+/// An entry that is only locked and unlocked on request, declared with
+/// `restriction = "manual"` or by giving no restriction at all.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManualRestriction {
+    #[serde(default, rename = "restriction")]
+    _restriction: Option<ManualKind>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ManualKind {
+    Manual,
+}
+
+/// Where the `cool_time` of a dynamic entry is measured from.
+///
+/// With `period = "30m"` and `cool_time = "2h"`, unlocking at 10:00 allows the
+/// next unlock at 12:00 with `unlock_start`, but only at 12:30 with `unlock_end`.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CooldownFrom {
+    #[default]
+    UnlockStart,
+    UnlockEnd,
 }
 
 #[derive(Deserialize)]
 pub struct Entry {
     pub domains: Vec<String>,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub signal_on_lock: Option<ProcessSignal>,
+    pub signal_on_unlock: Option<ProcessSignal>,
+    #[serde(default, deserialize_with = "deserialize_opt_hm")]
+    pub unlock_delay: Option<Duration>,
+    #[serde(default)]
+    pub require_reason: bool,
+    pub redirect_ip: Option<IpAddr>,
+    #[serde(default, deserialize_with = "deserialize_opt_hm")]
+    pub nag_after: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_opt_hm")]
+    pub lock_grace: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_holidays")]
+    pub holidays: HashSet<NaiveDate>,
+    pub hook_cwd: Option<PathBuf>,
+    /// Overrides the config's `backend` for this entry's domains.
+    pub backend: Option<Backend>,
     #[serde(flatten)]
     pub restriction: Restriction,
 }
 
+impl Entry {
+    pub fn is_holiday(&self, time: &LocalTime) -> bool {
+        self.holidays.contains(&time.date().naive_local())
+    }
+
+    /// Whether a static entry is scheduled to be unlocked at `time`.
+    pub fn in_unlock_window(&self, time: &LocalTime) -> Option<bool> {
+        match &self.restriction {
+            Restriction::Static { unlock } => {
+                Some(self.is_holiday(time) || unlock.iter().any(|d| d.contains(time)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn deserialize_holidays<'a, D>(deserializer: D) -> Result<HashSet<NaiveDate>, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    use serde::de::Error;
+    let path: PathBuf = Deserialize::deserialize(deserializer)?;
+    read_holidays(&path).map_err(|e| Error::custom(format!("{:#}", e)))
+}
+
+// A holidays file lists one ISO 8601 date (`2020-12-25`) per line. Blank lines
+// and lines starting with `#` are ignored.
+fn read_holidays(path: &Path) -> Result<HashSet<NaiveDate>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read holidays file {}", path.display()))?;
+
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            NaiveDate::parse_from_str(line, "%Y-%m-%d")
+                .with_context(|| format!("Invalid date `{}` in {}", line, path.display()))
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+pub struct ProcessSignal {
+    pub process: String,
+    #[serde(deserialize_with = "deserialize_signal")]
+    pub signal: i32,
+}
+
+fn deserialize_signal<'a, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'a>,
+{
+    use serde::de::Error;
+    let string: String = Deserialize::deserialize(deserializer)?;
+    let name = string.trim_start_matches("SIG");
+    let signal = match name {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "TSTP" => libc::SIGTSTP,
+        _ => return Err(Error::custom(format!("Unknown signal: {}", string))),
+    };
+    Ok(signal)
+}
+
+pub const CONFIG_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct ConfigVersion {
+    #[serde(default = "default_config_version")]
+    version: u32,
+}
+
+/// Rejects configs written for a newer senklot before they are parsed in full,
+/// since their new fields would otherwise fail in confusing ways.
+pub fn check_config_version(source: &str) -> Result<()> {
+    let ConfigVersion { version } = toml::from_str(source)?;
+    if version > CONFIG_VERSION {
+        return Err(anyhow!(
+            "Config version {} is newer than the supported version {}; upgrade senklot",
+            version,
+            CONFIG_VERSION
+        ));
+    }
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct Config {
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     pub after_lock: Option<String>,
     pub after_unlock: Option<String>,
+    pub on_nag: Option<String>,
+    /// Working directory for hooks, unless an entry sets its own.
+    pub hook_cwd: Option<PathBuf>,
+    /// Run before a scheduled lock or unlock; exiting with `VETO_EXIT_CODE`
+    /// postpones the transition to the next tick.
+    pub before_lock: Option<String>,
+    pub before_unlock: Option<String>,
+    pub max_vetoes: Option<u32>,
+    #[serde(default = "default_redirect")]
+    pub redirect: IpAddr,
+    /// Also lock domains over IPv6 by redirecting them to `redirect_v6`.
+    #[serde(default)]
+    pub block_ipv6: bool,
+    #[serde(default = "default_redirect_v6")]
+    pub redirect_v6: Ipv6Addr,
     #[serde(deserialize_with = "deserialize_secs", default = "default_interval")]
     pub interval: Duration,
+    #[serde(default = "default_max_hosts_lines")]
+    pub max_hosts_lines: usize,
+    /// Re-read /etc/hosts just before writing it and merge onto the fresh
+    /// content if another program changed it in the meantime.
+    #[serde(default = "default_verify_hosts_before_write")]
+    pub verify_hosts_before_write: bool,
+    /// Flush the resolver's cache after writing /etc/hosts, for systems such as
+    /// systemd-resolved that would otherwise keep serving cached answers.
+    #[serde(default)]
+    pub flush_dns: bool,
+    #[serde(default = "default_flush_dns_command")]
+    pub flush_dns_command: String,
+    #[serde(default)]
+    pub unlock_on_exit: bool,
+    /// How many hooks may run at once; the rest wait their turn. Unbounded if unset.
+    pub max_concurrent_hooks: Option<usize>,
+    /// Require a code, printed to the daemon log at every start, for unlocks.
+    #[serde(default)]
+    pub unlock_code: bool,
+    #[serde(default)]
+    pub initial_state: InitialState,
+    #[serde(default)]
+    pub backend: Backend,
+    /// Run after rewriting `paths.dnsmasq_conf`. A SIGHUP does not make
+    /// dnsmasq re-read `address=` lines, hence a restart.
+    #[serde(default = "default_dnsmasq_reload_command")]
+    pub dnsmasq_reload_command: String,
+    #[serde(default)]
+    pub paths: Paths,
     #[serde(flatten)]
     pub entries: HashMap<String, Entry>,
+    #[serde(skip)]
+    pub source: String,
+    /// `source_hash` of `source` and the holidays it loaded, which tells whether
+    /// a reload has anything new.
+    #[serde(skip)]
+    pub source_hash: u64,
+}
+
+/// How locked domains are blocked.
+///
+/// `hosts` redirects them in /etc/hosts. `nftables` drops outbound traffic to
+/// their addresses instead, which programs ignoring /etc/hosts cannot bypass,
+/// but which also blocks other sites served from the same addresses. `dnsmasq`
+/// makes a running dnsmasq answer for them, for every client on the network.
+///
+/// Entries may each pick their own, in which case all of them run side by side.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Hosts,
+    #[serde(alias = "nft")]
+    Nftables,
+    Dnsmasq,
+}
+
+/// The state given to an entry senklot has never locked or unlocked before.
+///
+/// `schedule` follows the entry's restriction, so a fresh dynamic entry stays
+/// locked until it is unlocked and a manual entry stays unmanaged until requested.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialState {
+    Locked,
+    Unlocked,
+    #[default]
+    Schedule,
+}
+
+pub const VETO_EXIT_CODE: i32 = 100;
+
+const PROTECTED_DOMAINS: &[&str] = &["localhost", "localhost.localdomain"];
+
+/// Whether `domain` can stand in the name column of a hosts line: dot-separated
+/// labels of letters, digits, `-` and `_`, or an IP address.
+fn is_valid_domain(domain: &str) -> bool {
+    if domain.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+fn hostname() -> Option<String> {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(hostname.trim().to_owned())
+}
+
+/// Covers the holidays files too, which can change while the config does not.
+pub fn source_hash(source: &str, entries: &HashMap<String, Entry>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+
+    let mut names: Vec<_> = entries.keys().collect();
+    names.sort();
+    for name in names {
+        let mut holidays: Vec<_> = entries[name].holidays.iter().collect();
+        holidays.sort();
+        holidays.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<()> {
+        let hostname = hostname();
+
+        for (name, entry) in &self.entries {
+            if let Some(domain) = entry.domains.iter().find(|d| !is_valid_domain(d)) {
+                return Err(anyhow!(
+                    "Entry `{}`: field `domains`: `{}` is not a valid hostname",
+                    name,
+                    domain
+                ));
+            }
+
+            if entry.force {
+                continue;
+            }
+
+            for domain in &entry.domains {
+                if PROTECTED_DOMAINS.contains(&domain.as_str())
+                    || hostname.as_deref() == Some(domain.as_str())
+                {
+                    return Err(anyhow!(
+                        "Entry `{}`: field `domains`: senklot refuses to block `{}` (set `force = true` to override)",
+                        name,
+                        domain
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lowercases every domain, since DNS names are case-insensitive.
+    pub fn normalize_domains(&mut self) {
+        for entry in self.entries.values_mut() {
+            for domain in &mut entry.domains {
+                *domain = domain.to_lowercase();
+            }
+        }
+    }
+
+    /// Fills in per-entry settings that fall back to a global one.
+    pub fn inherit_defaults(&mut self) {
+        for entry in self.entries.values_mut() {
+            if entry.hook_cwd.is_none() {
+                entry.hook_cwd = self.hook_cwd.clone();
+            }
+        }
+    }
+
+    /// Whether each entry's schedule wants it locked at `now`, without touching
+    /// the system. Dynamic entries count as locked since nothing has unlocked
+    /// them here, and manual entries are left out.
+    pub fn state_at(&self, now: LocalTime) -> HashMap<String, bool> {
+        self.entries
+            .iter()
+            .filter_map(|(name, entry)| {
+                let is_locked = match &entry.restriction {
+                    Restriction::Static { .. } => !entry.in_unlock_window(&now)?,
+                    Restriction::Dynamic { .. } => true,
+                    Restriction::Manual(_) => return None,
+                };
+                Some((name.clone(), is_locked))
+            })
+            .collect()
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for (name, entry) in &self.entries {
+            for domain in &entry.domains {
+                if domain.parse::<IpAddr>().is_ok() {
+                    warnings.push(format!(
+                        "Entry `{}` lists the IP address `{}`, but /etc/hosts cannot block direct connections to an IP; use a firewall for that",
+                        name, domain
+                    ));
+                }
+            }
+
+            let mut seen = HashSet::new();
+            for domain in &entry.domains {
+                if !seen.insert(domain) {
+                    warnings.push(format!(
+                        "Entry `{}` lists `{}` more than once",
+                        name, domain
+                    ));
+                }
+            }
+
+            if let Restriction::Static { unlock } = &entry.restriction {
+                if covers_whole_day(unlock) {
+                    warnings.push(format!(
+                        "Entry `{}` is unlocked all day, so it is never blocked",
+                        name
+                    ));
+                }
+            }
+        }
+
+        // Shared domains are allowed: they stay locked while any of their
+        // entries is locked, and their hosts lines are marked with the first
+        // entry by name.
+        let mut owners = HashMap::<_, Vec<_>>::new();
+        for (name, entry) in &self.entries {
+            for domain in &entry.domains {
+                owners.entry(domain).or_default().push(name.as_str());
+            }
+        }
+        let mut shared: Vec<_> = owners
+            .into_iter()
+            .map(|(domain, mut names)| {
+                names.sort();
+                names.dedup();
+                (domain, names)
+            })
+            .filter(|(_, names)| names.len() > 1)
+            .collect();
+        shared.sort();
+        for (domain, names) in shared {
+            warnings.push(format!(
+                "`{}` is listed by the entries `{}`; it stays locked while any of them is locked",
+                domain,
+                names.join("`, `")
+            ));
+        }
+
+        warnings
+    }
 }
 
 pub fn deserialize_secs<'a, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -93,41 +649,302 @@ where
     Ok(Duration::seconds(seconds))
 }
 
+pub fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
 pub fn default_interval() -> Duration {
     Duration::seconds(60)
 }
 
+pub fn default_redirect() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+pub fn default_redirect_v6() -> Ipv6Addr {
+    Ipv6Addr::LOCALHOST
+}
+
+pub fn default_max_hosts_lines() -> usize {
+    10000
+}
+
+pub fn default_verify_hosts_before_write() -> bool {
+    true
+}
+
+pub fn default_flush_dns_command() -> String {
+    "resolvectl flush-caches".to_owned()
+}
+
+pub fn default_dnsmasq_reload_command() -> String {
+    "systemctl restart dnsmasq".to_owned()
+}
+
 named!(two_digits(&str) -> u32, map_res!(take!(2), u32::from_str));
 named!(time(&str) -> Time,
     map_res!(tuple!(two_digits, tag!(":"), two_digits), |(h, _, m)| {
         if h > 23  {
             return Err(anyhow!("Invalid hours"));
         }
-        if m > 60  {
+        if m > 59  {
             return Err(anyhow!("Invalid minutes"));
         }
 
         Ok(Time::from_hms(h, m, 0))
     })
 );
+named!(weekday(&str) -> Weekday, map_res!(take!(3), Weekday::from_str));
+named!(weekdays(&str) -> Vec<Weekday>,
+    map!(tuple!(separated_nonempty_list!(tag!(","), weekday), space1), |(days, _)| days)
+);
 named!(static_duration(&str) -> StaticDuration,
-    map!(tuple!(time, tag!("-"), time), |(b, _, e)| {
-        StaticDuration{begin: b, end: e}
+    map!(tuple!(opt!(weekdays), time, tag!("-"), time), |(w, b, _, e)| {
+        StaticDuration{begin: b, end: e, weekdays: w}
     })
 );
 named!(float(&str) -> f64,
-    map_res!(recognize!(tuple!(digit1, opt!(tuple!(tag!("."), digit0)))), f64::from_str)
+    map_res!(recognize!(tuple!(digit1, opt!(complete!(tuple!(tag!("."), digit0))))), f64::from_str)
 );
 named!(unit(&str) -> DurationUnit,
-    alt!( map!(tag!("h"), |_| DurationUnit::Hours)
+    alt!( map!(tag!("d"), |_| DurationUnit::Days)
+        | map!(tag!("h"), |_| DurationUnit::Hours)
         | map!(tag!("m") ,|_| DurationUnit::Minutes)
+        | map!(tag!("s"), |_| DurationUnit::Seconds)
         )
 );
-named!(mh_duration(&str) -> Duration,
+named!(duration_part(&str) -> Duration,
     map!(tuple!(float, unit), |(d, u)| {
         match u {
-            DurationUnit::Minutes => Duration::minutes(d.trunc() as i64),
-            DurationUnit::Hours   => Duration::hours(d.trunc() as i64) + Duration::minutes((d.fract() / 60.).trunc() as i64)
+            DurationUnit::Seconds => Duration::milliseconds((d * 1000.).round() as i64),
+            DurationUnit::Minutes => Duration::minutes(d.trunc() as i64) + Duration::seconds((d.fract() * 60.).round() as i64),
+            DurationUnit::Hours   => Duration::hours(d.trunc() as i64) + Duration::minutes((d.fract() * 60.).round() as i64),
+            DurationUnit::Days    => Duration::days(d.trunc() as i64) + Duration::minutes((d.fract() * 24. * 60.).round() as i64)
         }
     })
 );
+// Compound durations such as `1h30m` add up their parts.
+named!(mh_duration(&str) -> Duration,
+    fold_many1!(duration_part, Duration::zero(), |total, part| total + part)
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A time in the week of Monday 2020-06-01.
+    fn at(day: u32, h: u32, m: u32) -> LocalTime {
+        Local.ymd(2020, 6, day).and_hms(h, m, 0)
+    }
+
+    fn window(string: &str) -> StaticDuration {
+        parse_static_duration(string).unwrap()
+    }
+
+    #[test]
+    fn window_includes_its_begin_and_excludes_its_end() {
+        let window = window("09:00-17:00");
+        assert!(!window.contains(&at(1, 8, 59)));
+        assert!(window.contains(&at(1, 9, 0)));
+        assert!(window.contains(&at(1, 16, 59)));
+        assert!(!window.contains(&at(1, 17, 0)));
+    }
+
+    #[test]
+    fn window_runs_past_midnight() {
+        let window = window("22:00-02:00");
+        assert!(window.contains(&at(1, 22, 0)));
+        assert!(window.contains(&at(2, 1, 59)));
+        assert!(!window.contains(&at(2, 2, 0)));
+        assert!(!window.contains(&at(2, 12, 0)));
+        assert!(!window.contains(&at(2, 21, 59)));
+    }
+
+    #[test]
+    fn weekday_window_applies_on_its_days_only() {
+        let window = window("Sat,Sun 10:00-18:00");
+        assert_eq!(window.weekdays, Some(vec![Weekday::Sat, Weekday::Sun]));
+        assert!(!window.contains(&at(5, 12, 0)));
+        assert!(window.contains(&at(6, 10, 0)));
+        assert!(window.contains(&at(7, 17, 59)));
+        assert!(!window.contains(&at(7, 18, 0)));
+        assert!(!window.contains(&at(8, 12, 0)));
+    }
+
+    #[test]
+    fn weekday_window_past_midnight_belongs_to_its_start_day() {
+        let window = window("Fri 22:00-02:00");
+        assert!(!window.contains(&at(4, 23, 0)));
+        assert!(!window.contains(&at(5, 1, 0)));
+        assert!(window.contains(&at(5, 22, 0)));
+        assert!(window.contains(&at(6, 1, 59)));
+        assert!(!window.contains(&at(6, 2, 0)));
+        assert!(!window.contains(&at(6, 22, 0)));
+    }
+
+    #[test]
+    fn window_without_weekdays_applies_every_day() {
+        let window = window("10:00-18:00");
+        assert_eq!(window.weekdays, None);
+        for day in 1..=7 {
+            assert!(window.contains(&at(day, 12, 0)));
+        }
+    }
+
+    #[test]
+    fn malformed_weekday_window_is_rejected() {
+        assert!(parse_static_duration("Sat,Sun10:00-18:00").is_err());
+        assert!(parse_static_duration("Xyz 10:00-18:00").is_err());
+        assert!(parse_static_duration("Sat, 10:00-18:00").is_err());
+    }
+
+    fn config(source: &str) -> Config {
+        let mut config: Config = toml::from_str(source).unwrap();
+        config.normalize_domains();
+        config
+    }
+
+    #[test]
+    fn protected_domains_are_refused_unless_forced() {
+        let e = config("[local]\ndomains = [\"LocalHost\"]\n")
+            .validate()
+            .unwrap_err();
+        assert!(e.to_string().contains("refuses to block `localhost`"));
+
+        assert!(config("[local]\ndomains = [\"localhost\"]\nforce = true\n")
+            .validate()
+            .is_ok());
+        assert!(config("[sns]\ndomains = [\"example.com\"]\n")
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn duration_without_unit_asks_for_one() {
+        for bare in &["30", "1.5"] {
+            let e = parse_duration(bare).unwrap_err();
+            assert!(e.to_string().contains("requires a unit (d/h/m/s)"), "{}", e);
+        }
+        let e = parse_duration("30x").unwrap_err();
+        assert!(e.to_string().starts_with("expected a duration"));
+    }
+
+    #[test]
+    fn static_entry_is_unlocked_on_holidays() {
+        let holidays =
+            std::env::temp_dir().join(format!("senklot-test-{}-holidays", std::process::id()));
+        fs::write(&holidays, "# national holidays\n\n2020-06-02\n").unwrap();
+        let config = config(&format!(
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"06:00-07:00\"]\nholidays = \"{}\"\n",
+            holidays.display()
+        ));
+        fs::remove_file(&holidays).unwrap();
+
+        let entry = &config.entries["sns"];
+        assert_eq!(entry.in_unlock_window(&at(1, 12, 0)), Some(false));
+        assert_eq!(entry.in_unlock_window(&at(2, 12, 0)), Some(true));
+        assert_eq!(entry.in_unlock_window(&at(3, 6, 30)), Some(true));
+    }
+
+    #[test]
+    fn windows_covering_the_whole_day_are_detected() {
+        let covers = |windows: &[&str]| {
+            let windows: Vec<_> = windows.iter().map(|w| window(w)).collect();
+            covers_whole_day(&windows)
+        };
+        assert!(covers(&["00:00-12:00", "12:00-00:00"]));
+        assert!(covers(&["22:00-10:00", "09:00-23:00"]));
+        assert!(covers(&["00:00-00:00"]));
+        assert!(!covers(&["00:00-12:00", "12:01-00:00"]));
+        assert!(!covers(&["Sat,Sun 00:00-12:00", "12:00-00:00"]));
+
+        let config = config(
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"00:00-12:00\", \"12:00-00:00\"]\n",
+        );
+        assert_eq!(
+            config.warnings(),
+            ["Entry `sns` is unlocked all day, so it is never blocked"]
+        );
+    }
+
+    #[test]
+    fn ip_address_in_domains_is_warned_about() {
+        let config = config("[sns]\ndomains = [\"example.com\", \"192.0.2.1\", \"2001:db8::1\"]\n");
+        assert!(config.validate().is_ok());
+
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("Entry `sns` lists the IP address `192.0.2.1`"));
+        assert!(warnings[1].starts_with("Entry `sns` lists the IP address `2001:db8::1`"));
+    }
+
+    #[test]
+    fn newer_config_version_is_rejected() {
+        assert!(check_config_version("[sns]\ndomains = []\n").is_ok());
+        assert!(check_config_version(&format!("version = {}\n", CONFIG_VERSION)).is_ok());
+
+        let e = check_config_version(&format!("version = {}\n", CONFIG_VERSION + 1)).unwrap_err();
+        assert!(e.to_string().contains("upgrade senklot"));
+        assert_eq!(config("[sns]\ndomains = []\n").version, CONFIG_VERSION);
+    }
+
+    #[test]
+    fn fractional_durations_are_rounded_to_the_smaller_unit() {
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("1.5m").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_duration("0.5d").unwrap(), Duration::hours(12));
+    }
+
+    #[test]
+    fn window_times_must_be_within_the_day() {
+        assert!(parse_static_duration("00:00-23:59").is_ok());
+        for bad in &["00:00-24:00", "10:60-11:00", "25:00-01:00"] {
+            assert!(parse_static_duration(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn state_at_follows_static_windows_and_skips_manual_entries() {
+        let config = config(
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"06:00-07:00\"]\n\
+             [news]\ndomains = [\"example.org\"]\nperiod = \"30m\"\ncool_time = \"2h\"\n\
+             [games]\ndomains = [\"example.net\"]\n",
+        );
+
+        let state = |is_locked| -> HashMap<_, _> {
+            vec![("sns".to_owned(), is_locked), ("news".to_owned(), true)]
+                .into_iter()
+                .collect()
+        };
+        assert_eq!(config.state_at(at(1, 6, 30)), state(false));
+        assert_eq!(config.state_at(at(1, 7, 0)), state(true));
+    }
+
+    #[test]
+    fn durations_accept_every_unit_and_add_up_their_parts() {
+        assert_eq!(parse_duration("45s").unwrap(), Duration::seconds(45));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::days(2));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::minutes(90));
+        assert_eq!(
+            parse_duration("1d2h3m4s").unwrap(),
+            Duration::days(1) + Duration::hours(2) + Duration::minutes(3) + Duration::seconds(4)
+        );
+    }
+
+    #[test]
+    fn shared_and_repeated_domains_are_warned_about() {
+        let config = config(
+            "[sns]\ndomains = [\"example.com\", \"example.org\"]\n\
+             [news]\ndomains = [\"example.org\", \"example.net\", \"example.net\"]\n",
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.warnings(),
+            [
+                "Entry `news` lists `example.net` more than once",
+                "`example.org` is listed by the entries `news`, `sns`; it stays locked while any of them is locked",
+            ]
+        );
+    }
+}
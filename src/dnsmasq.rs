@@ -0,0 +1,151 @@
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use crate::blocker::*;
+use crate::util::*;
+
+/// Answers queries for locked domains with an unspecified address through
+/// `address=` lines in a dnsmasq config fragment, blocking them for every
+/// client of the resolver.
+pub struct Dnsmasq {
+    conf: PathBuf,
+    tmp: PathBuf,
+    reload_command: String,
+    locked: BTreeMap<String, bool>,
+    /// What dnsmasq was last reloaded with; a failed reload is retried.
+    applied: Option<String>,
+}
+
+impl Dnsmasq {
+    pub fn new(conf: PathBuf, tmp: PathBuf, reload_command: String) -> Dnsmasq {
+        Dnsmasq {
+            conf,
+            tmp,
+            reload_command,
+            locked: BTreeMap::new(),
+            applied: None,
+        }
+    }
+
+    fn export(&self) -> String {
+        let mut content = String::from("# Written by senklot; local changes are overwritten.\n");
+        for (domain, _) in self.locked.iter().filter(|(_, locked)| **locked) {
+            content += &format!("address=/{0}/0.0.0.0\naddress=/{0}/::\n", domain);
+        }
+        content
+    }
+
+    fn reload(&self) -> Result<()> {
+        let status = process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.reload_command)
+            .status()
+            .context("Unable to reload dnsmasq")?;
+        if !status.success() {
+            return Err(anyhow!(
+                "Unable to reload dnsmasq: `{}` {}",
+                self.reload_command,
+                status
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Blocker for Dnsmasq {
+    fn set_locked(&mut self, domain: &str, locked: bool) {
+        self.locked.insert(domain.to_owned(), locked);
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let content = self.export();
+        if self.applied.as_ref() == Some(&content) {
+            return Ok(());
+        }
+
+        // dnsmasq may restart at any time, so it must never see a partial file.
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.conf)
+            .and_then(|_| replace_file(&self.conf, &self.tmp, content.as_bytes(), None))
+            .with_context(|| format!("Unable to write {}", self.conf.display()))?;
+        self.reload()?;
+
+        info!("Updated {} and reloaded dnsmasq", self.conf.display());
+        self.applied = Some(content);
+        Ok(())
+    }
+
+    fn is_locked(&self, domain: &str) -> bool {
+        self.locked.get(domain).cloned().unwrap_or(false)
+    }
+
+    fn remove(&mut self) -> Result<()> {
+        match fs::remove_file(&self.conf) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Unable to remove {}", self.conf.display()))
+            }
+            _ => {}
+        }
+        self.reload()?;
+        info!("Removed {} and reloaded dnsmasq", self.conf.display());
+        self.applied = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::Paths;
+    use crate::util::tests::temp_path;
+
+    fn dnsmasq(name: &str) -> Dnsmasq {
+        let paths = Paths {
+            dnsmasq_conf: temp_path(name),
+            ..Paths::default()
+        };
+        Dnsmasq::new(
+            paths.dnsmasq_conf.clone(),
+            paths.dnsmasq_tmp(),
+            "true".to_owned(),
+        )
+    }
+
+    #[test]
+    fn commit_replaces_the_fragment_through_a_hidden_file() {
+        let mut dnsmasq = dnsmasq("dnsmasq-commit.conf");
+        assert!(dnsmasq
+            .tmp
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with('.'));
+
+        dnsmasq.set_locked("example.com", true);
+        dnsmasq.set_locked("example.org", false);
+        dnsmasq.commit().unwrap();
+        let content = fs::read_to_string(&dnsmasq.conf).unwrap();
+        assert!(content.contains("address=/example.com/0.0.0.0\n"));
+        assert!(!content.contains("example.org"));
+        assert!(!dnsmasq.tmp.exists());
+
+        dnsmasq.remove().unwrap();
+        assert!(!dnsmasq.conf.exists());
+    }
+
+    #[test]
+    fn failed_reload_is_reported() {
+        let mut dnsmasq = dnsmasq("dnsmasq-reload.conf");
+        dnsmasq.reload_command = "false".to_owned();
+        assert!(dnsmasq.commit().is_err());
+        assert!(dnsmasq.applied.is_none());
+        let _ = fs::remove_file(&dnsmasq.conf);
+    }
+}
@@ -0,0 +1,5 @@
+//! senklot's config and scheduling, for programs that want to decide what to
+//! block without running the daemon; see [`config::Config::state_at`].
+
+pub mod config;
+pub mod paths;
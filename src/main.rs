@@ -1,62 +1,255 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::offset::Local;
+use chrono::Duration;
 use crossbeam::channel;
 use crossbeam::channel::{select, tick};
 use daemonize::Daemonize;
+use env_logger::{Env, Target};
+use log::{error, info, warn};
 use notify::event::*;
 use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::de::DeserializeOwned;
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGCHLD, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use std::env;
 use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use std::os::unix::net;
+use std::io::SeekFrom;
 use std::net::Shutdown;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time;
 
+mod blocker;
 mod cli;
-mod config;
+mod clock;
+mod dnsmasq;
 mod message;
+mod nftables;
 mod state;
 mod util;
 
+use senklot::{config, paths};
+
 use cli::*;
+use clock::*;
 use config::*;
 use message::*;
+use paths::*;
 use state::*;
 use util::*;
 
 fn main() -> Result<()> {
-    let args = get_args()?;
+    let cli = get_args()?;
+    init_logger(log_target(&cli.command));
+
+    if let Args::Version {} = cli.command {
+        print_version();
+        return Ok(());
+    }
+    if let Args::Validate(options) = cli.command {
+        return run_validate(options);
+    }
 
-    let config = read_config_file().context("Unable to read config")?;
-    let config = parse_config(&config).context("Parse error in config")?;
+    let config = read_config_file(Path::new(CONFIG_FILE)).context("Unable to read config")?;
+    let mut config = parse_config(&config).context("Parse error in config")?;
+    if let Some(hosts_file) = cli.hosts_file {
+        config.paths.hosts_file = hosts_file;
+    }
+    if let Some(state_file) = cli.state_file {
+        config.paths.state_file = state_file;
+    }
+    if let Some(socket) = cli.socket {
+        config.paths.socket = socket;
+    }
 
-    match args {
-        Args::Start {} => run_as_daemon(config),
-        Args::Unlock { name } => run_unlock(config, &name),
+    match cli.command {
+        Args::Start(options) => run_as_daemon(config, options),
+        Args::Unlock(options) => run_unlock(config, options),
+        Args::Lock(options) => run_lock(config, options),
+        Args::Remaining { name } => run_remaining(config, &name),
+        Args::Why { name } => run_why(config, &name),
+        Args::Schedule {} => run_schedule(config),
+        Args::FastTick { duration } => run_fast_tick(config, &duration),
+        Args::TailLog {} => run_tail_log(config),
+        Args::Health(options) => run_health(config, options),
+        Args::ExportHosts(options) => run_export_hosts(config, options),
+        Args::Focus(options) => run_focus(config, options),
+        Args::Stats {} => run_stats(config),
+        Args::DiffConfig {} => run_diff_config(config),
+        Args::TestHook(options) => run_test_hook(config, options),
+        Args::Status {} => run_status(config),
+        Args::Simulate(options) => run_simulate(config, options),
+        Args::HostsMap {} => run_hosts_map(config),
+        Args::FormatHosts {} => run_format_hosts(config),
+        Args::Check {} => run_check(config),
+        Args::Version {} | Args::Validate(_) => unreachable!(),
     }
 }
 
-fn run_as_daemon(config: Config) -> Result<()> {
-    let state = State::read_with_config(&config, "/var/lib/senklot")
-        .context("Unable to read state file")?;
+/// Logs at `info` and above unless `$SENKLOT_LOG` says otherwise, e.g.
+/// `SENKLOT_LOG=debug`.
+///
+/// `SENKLOT_LOG_FORMAT=json` writes one JSON object per line instead of text,
+/// for log aggregators.
+fn init_logger(target: Target) {
+    let mut builder = env_logger::Builder::from_env(Env::new().filter_or("SENKLOT_LOG", "info"));
+    builder.target(target);
+    if env::var("SENKLOT_LOG_FORMAT").as_deref() == Ok("json") {
+        builder.format(|buf, record| writeln!(buf, "{}", json_log_line(record)));
+    }
+    builder.init();
+}
 
-    main_loop(config, state)?;
+/// Stdout for a daemonized `start`, which redirects it to its log; stderr
+/// otherwise, keeping logs out of the output of commands and foreground runs.
+fn log_target(command: &Args) -> Target {
+    match command {
+        Args::Start(options) if !options.foreground => Target::Stdout,
+        _ => Target::Stderr,
+    }
+}
+
+fn json_log_line(record: &log::Record) -> String {
+    let (entry, action) = match log_scope() {
+        Some((entry, action)) => (Some(entry), Some(action)),
+        None => (None, None),
+    };
+    serde_json::json!({
+        "timestamp": Local::now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "message": record.args().to_string(),
+        "entry": entry,
+        "action": action,
+    })
+    .to_string()
+}
+
+fn print_version() {
+    println!("senklot {}", env!("CARGO_PKG_VERSION"));
+    println!("commit: {}", env!("SENKLOT_GIT_COMMIT"));
+    println!("rustc: {}", env!("SENKLOT_RUSTC_VERSION"));
+    println!("features: {}", env!("SENKLOT_FEATURES"));
+}
+
+fn run_as_daemon(config: Config, options: StartOptions) -> Result<()> {
+    let state = State::read_with_config(&config).context("Unable to read state file")?;
+
+    main_loop(config, state, options)?;
 
     Ok(())
 }
 
-fn run_unlock(_: Config, name: &str) -> Result<()> {
-    let mut stream = net::UnixStream::connect("/var/lib/senklot.socket")?;
-    stream.write_all(name.as_bytes())?;
-    stream.shutdown(Shutdown::Write)?;
-    let response = {
-        let mut response = String::new();
-        stream.read_to_string(&mut response)?;
-        bincode::deserialize(&response.as_bytes())?
+fn run_unlock(config: Config, options: UnlockOptions) -> Result<()> {
+    if options.all_scheduled {
+        let targets = &options.targets;
+        if targets.name.is_some() || targets.stdin || targets.tag.is_some() {
+            return Err(anyhow!(
+                "--all-scheduled cannot be combined with an entry name, --stdin or --tag"
+            ));
+        }
+        return unlock_scheduled(&config.paths);
+    }
+
+    let secs = match &options.duration {
+        Some(duration) => Some(parse_duration(duration)?.num_seconds()),
+        None => None,
+    };
+    let code = match &options.code {
+        Some(code) => Some(code.clone()),
+        None if config.unlock_code => Some(prompt("Unlock code: ")?),
+        None => None,
     };
 
-    match response {
-        UnlockResponse::Success { locked_at } => {
-            println!("{}", locked_at);
+    for_each_target(&config, &options.targets, |name| {
+        let reason = match &options.reason {
+            Some(reason) => Some(reason.clone()),
+            None if config
+                .entries
+                .get(name)
+                .and_if(|entry| entry.require_reason) =>
+            {
+                Some(prompt_reason(name)?)
+            }
+            None => None,
+        };
+        unlock_entry(&config.paths, name, reason, code.clone(), secs)
+    })
+}
+
+fn prompt(message: &str) -> Result<String> {
+    print!("{}", message);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_owned())
+}
+
+fn unlock_scheduled(paths: &Paths) -> Result<()> {
+    let response: UnlockScheduledResponse = send_request(paths, &Request::UnlockScheduled)?;
+
+    if response.results.is_empty() {
+        println!("No locked entries are scheduled to be unlocked");
+    }
+    for (name, error) in response.results {
+        match error {
+            Some(cause) => println!("{}: {}", name, cause),
+            None => println!("{}: Unlocked", name),
         }
+    }
+
+    Ok(())
+}
+
+fn prompt_reason(name: &str) -> Result<String> {
+    let reason = prompt(&format!("Reason for unlocking `{}`: ", name))?;
+    if reason.is_empty() {
+        return Err(anyhow!("A reason is required to unlock `{}`", name));
+    }
+
+    Ok(reason)
+}
+
+fn unlock_entry(
+    paths: &Paths,
+    name: &str,
+    reason: Option<String>,
+    code: Option<String>,
+    secs: Option<i64>,
+) -> Result<()> {
+    let mut response = send_request(
+        paths,
+        &Request::Unlock(UnlockRequest {
+            name: name.to_owned(),
+            reason,
+            code,
+            secs,
+        }),
+    )?;
+
+    if let UnlockResponse::Challenge { nonce, wait_secs } = response {
+        count_down(wait_secs)?;
+        response = send_request(
+            paths,
+            &Request::CompleteUnlock {
+                name: name.to_owned(),
+                nonce,
+                secs,
+            },
+        )?;
+    }
+
+    match response {
+        UnlockResponse::Success { locked_at } => match locked_at {
+            Some(locked_at) => println!("{}", locked_at),
+            None => println!("Unlocked until locked manually"),
+        },
         UnlockResponse::Fail { cause, unlocked_at } => {
             println!(
                 "{}\n{}",
@@ -67,133 +260,1181 @@ fn run_unlock(_: Config, name: &str) -> Result<()> {
                     .unwrap_or("")
             );
         }
+        UnlockResponse::Challenge { .. } => {
+            return Err(anyhow!("Received another challenge"));
+        }
+    }
+
+    Ok(())
+}
+
+fn for_each_target<F: FnMut(&str) -> Result<()>>(
+    config: &Config,
+    targets: &Targets,
+    mut action: F,
+) -> Result<()> {
+    let selectors = [targets.name.is_some(), targets.stdin, targets.tag.is_some()];
+    if selectors.iter().filter(|selected| **selected).count() > 1 {
+        return Err(anyhow!(
+            "Specify only one of an entry name, --stdin or --tag"
+        ));
+    }
+
+    if let Some(tag) = &targets.tag {
+        let mut names: Vec<_> = config
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.tags.contains(tag))
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+
+        if names.is_empty() {
+            println!("No entries are tagged `{}`", tag);
+            return Ok(());
+        }
+        return for_each_name(config, names, action);
+    }
+
+    if !targets.stdin {
+        let name = targets
+            .name
+            .as_deref()
+            .ok_or_else(|| anyhow!("Specify an entry name, --stdin or --tag"))?;
+        return action(name);
+    }
+
+    let mut names = String::new();
+    io::stdin().read_to_string(&mut names)?;
+    let names: Vec<_> = names
+        .lines()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if names.is_empty() {
+        println!("No entry names were given on stdin");
+        return Ok(());
+    }
+
+    for_each_name(config, names, action)
+}
+
+fn for_each_name<F: FnMut(&str) -> Result<()>>(
+    config: &Config,
+    names: Vec<&str>,
+    mut action: F,
+) -> Result<()> {
+    for name in names {
+        print!("{}: ", name);
+        if !config.entries.contains_key(name) {
+            println!("Unknown entry");
+            continue;
+        }
+        if let Err(e) = action(name) {
+            println!("{:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn count_down(secs: i64) -> Result<()> {
+    for remaining in (1..=secs).rev() {
+        print!("\rUnlocking in {}s ", remaining);
+        io::stdout().flush()?;
+        thread::sleep(time::Duration::from_secs(1));
+    }
+    println!();
+
+    Ok(())
+}
+
+fn run_lock(config: Config, options: LockOptions) -> Result<()> {
+    for_each_target(&config, &options.targets, |name| {
+        lock_entry(&config.paths, name)
+    })
+}
+
+fn lock_entry(paths: &Paths, name: &str) -> Result<()> {
+    let response = send_request(
+        paths,
+        &Request::Lock {
+            name: name.to_owned(),
+        },
+    )?;
+
+    match response {
+        LockResponse::Locked => println!("Locked"),
+        LockResponse::AlreadyLocked => println!("Already locked"),
+        LockResponse::Fail { cause } => println!("{}", cause),
     }
 
     Ok(())
 }
 
-fn main_loop(config: Config, mut state: State) -> Result<()> {
-    let channels = daemonize()?;
-    let ticker = tick(config.interval.to_std().unwrap());
-    let (_watcher, hosts_modified) = channels.hosts_modified;
-    let (_socket, unlock_request) = channels.unlock_request;
+fn run_why(config: Config, name: &str) -> Result<()> {
+    if !config.entries.contains_key(name) {
+        return Err(anyhow!("Unknown entry `{}`", name));
+    }
+
+    let response: WhyResponse = send_request(
+        &config.paths,
+        &Request::Why {
+            name: name.to_owned(),
+        },
+    )?;
+
+    match response.is_locked {
+        Some(is_locked) => println!("{} because: {}", lock_word(!is_locked), response.reason),
+        None => println!("unmanaged because: {}", response.reason),
+    }
+
+    Ok(())
+}
+
+fn run_remaining(config: Config, name: &str) -> Result<()> {
+    let response = send_request(
+        &config.paths,
+        &Request::Remaining {
+            name: name.to_owned(),
+        },
+    )?;
+
+    match response {
+        RemainingResponse::Unlocked { locked_at } => {
+            println!(
+                "{}",
+                format_duration(locked_at.signed_duration_since(Local::now()))
+            );
+        }
+        RemainingResponse::UnlockedIndefinitely => {
+            println!("unlocked until locked manually");
+        }
+        RemainingResponse::Locked => {
+            println!("not temporarily unlocked");
+        }
+        RemainingResponse::Fail { cause } => println!("{}", cause),
+    }
+
+    Ok(())
+}
+
+fn run_schedule(config: Config) -> Result<()> {
+    let schedule: Schedule = send_request(&config.paths, &Request::Schedule)?;
+    println!("{}", serde_json::to_string_pretty(&schedule)?);
+
+    Ok(())
+}
+
+fn run_fast_tick(config: Config, duration: &str) -> Result<()> {
+    let duration = parse_duration(duration)?;
+    let response: FastTickResponse = send_request(
+        &config.paths,
+        &Request::FastTick {
+            secs: duration.num_seconds(),
+        },
+    )?;
+    println!("{}", response.until);
+
+    Ok(())
+}
+
+fn run_tail_log(config: Config) -> Result<()> {
+    let path = config.paths.stdout_log();
+    let mut position = None;
+    let mut inode = None;
+
+    loop {
+        match File::open(&path) {
+            Ok(mut file) => {
+                let metadata = file.metadata()?;
+                // Start over when the daemon restarts (truncating the log) or it is rotated.
+                if inode != Some(metadata.ino()) || position.and_if(|p| metadata.len() < p) {
+                    inode = Some(metadata.ino());
+                    position = position.map(|_| 0);
+                }
+
+                let mut content = Vec::new();
+                file.seek(SeekFrom::Start(position.unwrap_or(0)))?;
+                file.read_to_end(&mut content)?;
+
+                let start = match position {
+                    Some(_) => 0,
+                    None => last_lines_start(&content, 10),
+                };
+                io::stdout().write_all(&content[start..])?;
+                io::stdout().flush()?;
+
+                position = Some(position.unwrap_or(0) + content.len() as u64);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("Unable to open {}", path.display())),
+        }
+
+        thread::sleep(time::Duration::from_millis(500));
+    }
+}
+
+fn last_lines_start(content: &[u8], lines: usize) -> usize {
+    content
+        .iter()
+        .enumerate()
+        .rev()
+        .skip(1)
+        .filter(|(_, byte)| **byte == b'\n')
+        .nth(lines - 1)
+        .map(|(i, _)| i + 1)
+        .unwrap_or(0)
+}
+
+fn run_health(config: Config, options: HealthOptions) -> Result<()> {
+    let timeout = time::Duration::from_secs(options.timeout);
+
+    match send_request_timeout::<PingResponse>(&config.paths, &Request::Ping, Some(timeout)) {
+        Ok(_) => {
+            println!("OK");
+            Ok(())
+        }
+        Err(e) => {
+            println!("NOT OK: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_export_hosts(config: Config, options: ExportHostsOptions) -> Result<()> {
+    let response: ExportHostsResponse = send_request(&config.paths, &Request::ExportHosts)?;
+
+    for exported in response.domains {
+        if exported.is_locked {
+            println!("{} {}", exported.redirect_ip, exported.domain);
+        } else if options.all {
+            println!("# {} {}", exported.redirect_ip, exported.domain);
+        }
+    }
+
+    Ok(())
+}
+
+fn run_focus(config: Config, options: FocusOptions) -> Result<()> {
+    let duration = parse_duration(&options.duration)?;
+    let response: FocusResponse = send_request(
+        &config.paths,
+        &Request::Focus {
+            secs: duration.num_seconds(),
+            tag: options.tag,
+        },
+    )?;
+
+    if response.entries.is_empty() {
+        println!("No entries to focus on");
+    } else {
+        println!("{}", response.until);
+        println!("{}", response.entries.join("\n"));
+    }
+
+    Ok(())
+}
+
+fn run_stats(config: Config) -> Result<()> {
+    let response: StatsResponse = send_request(&config.paths, &Request::Stats)?;
+
+    let mut names: Vec<_> = config.entries.keys().collect();
+    names.sort();
+    for name in names {
+        let rejected = response.rejected_unlocks.get(name).copied().unwrap_or(0);
+        println!("{}: {} rejected unlocks", name, rejected);
+    }
+
+    Ok(())
+}
+
+fn run_diff_config(config: Config) -> Result<()> {
+    let response: ConfigResponse = send_request(&config.paths, &Request::Config)?;
+    let running: toml::value::Table =
+        toml::from_str(&response.source).context("Unable to parse the running config")?;
+    let on_disk: toml::value::Table = toml::from_str(&config.source)?;
+
+    let changes = diff_tables(&running, &on_disk, "");
+    if changes.is_empty() {
+        println!("The running config matches the config on disk");
+    } else {
+        println!("{}", changes.join("\n"));
+    }
+
+    Ok(())
+}
+
+fn diff_tables(old: &toml::value::Table, new: &toml::value::Table, prefix: &str) -> Vec<String> {
+    use toml::Value;
+
+    let show = |key: &str, value: &Value| match value {
+        Value::Table(_) => format!("[{}]", key),
+        value => format!("{} = {}", key, value),
+    };
+
+    let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut changes = Vec::new();
+    for name in keys {
+        let key = format!("{}{}", prefix, name);
+        match (old.get(name), new.get(name)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                changes.extend(diff_tables(old, new, &format!("{}.", key)))
+            }
+            (Some(old), Some(new)) if old != new => {
+                changes.push(format!("- {}", show(&key, old)));
+                changes.push(format!("+ {}", show(&key, new)));
+            }
+            (Some(old), None) => changes.push(format!("- {}", show(&key, old))),
+            (None, Some(new)) => changes.push(format!("+ {}", show(&key, new))),
+            _ => {}
+        }
+    }
+
+    changes
+}
+
+fn run_test_hook(config: Config, options: TestHookOptions) -> Result<()> {
+    if !config.entries.contains_key(&options.name) {
+        return Err(anyhow!("Unknown entry `{}`", options.name));
+    }
+
+    let hook = match options.action.as_str() {
+        "lock" => &config.after_lock,
+        _ => &config.after_unlock,
+    };
+    let hook = match hook {
+        Some(hook) => hook,
+        None => {
+            println!("No after_{} hook is configured", options.action);
+            return Ok(());
+        }
+    };
+
+    let cwd = config.entries[&options.name].hook_cwd.as_deref();
+    let status = run_hook(hook, &options.name, cwd)?;
+    match status.code() {
+        Some(code) => println!("Exited with {}", code),
+        None => println!("Terminated by a signal"),
+    }
+
+    Ok(())
+}
+
+/// Prints one tab-separated line per entry: name, state, last locked,
+/// last unlocked and remaining cool-down in seconds, with `-` for none.
+fn run_status(config: Config) -> Result<()> {
+    let response: StatusResponse = send_request(&config.paths, &Request::Status)?;
+
+    let or_dash = |time: Option<LocalTime>| {
+        time.map(|time| time.to_rfc3339())
+            .unwrap_or_else(|| "-".to_owned())
+    };
+    for entry in response.entries {
+        let state = match entry.is_locked {
+            Some(true) => "locked",
+            Some(false) => "unlocked",
+            None => "unmanaged",
+        };
+        let tags = if entry.tags.is_empty() {
+            "-".to_owned()
+        } else {
+            entry.tags.join(",")
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            entry.name,
+            state,
+            or_dash(entry.last_locked),
+            or_dash(entry.last_unlocked),
+            entry
+                .cool_down_secs
+                .map(|secs| secs.to_string())
+                .unwrap_or_else(|| "-".to_owned()),
+            tags
+        );
+    }
+    if let Some(until) = response.fast_tick_until {
+        println!("Ticking fast until {}", until.to_rfc3339());
+    }
+
+    Ok(())
+}
+
+fn run_simulate(config: Config, options: SimulateOptions) -> Result<()> {
+    let start = Local::now();
+    let end = start + Duration::hours(options.hours);
+    let step = config.interval.max(Duration::seconds(1));
+
+    let mut names: Vec<_> = config.entries.keys().collect();
+    names.sort();
+    for name in names {
+        println!("{}", name);
+        match simulate(&config.entries[name], start, end, step) {
+            Some(timeline) => {
+                for (time, unlocked) in timeline {
+                    println!("  {} {}", time.format("%F %R"), lock_word(unlocked));
+                }
+            }
+            None => println!("  only changes on request"),
+        }
+    }
+
+    Ok(())
+}
+
+/// The state of `entry` at `start` followed by each change of its schedule,
+/// checked every `step` until `end`. `None` for entries without a schedule.
+fn simulate(
+    entry: &Entry,
+    start: LocalTime,
+    end: LocalTime,
+    step: Duration,
+) -> Option<Vec<(LocalTime, bool)>> {
+    let clock = FixedClock::new(start);
+    let mut unlocked = entry.in_unlock_window(&clock.now())?;
+    let mut timeline = vec![(start, unlocked)];
+
+    clock.advance(step);
+    while clock.now() <= end {
+        let now_unlocked = entry.in_unlock_window(&clock.now()) == Some(true);
+        if now_unlocked != unlocked {
+            unlocked = now_unlocked;
+            timeline.push((clock.now(), unlocked));
+        }
+        clock.advance(step);
+    }
+
+    Some(timeline)
+}
+
+fn lock_word(unlocked: bool) -> &'static str {
+    if unlocked {
+        "unlocked"
+    } else {
+        "locked"
+    }
+}
+
+fn run_hosts_map(config: Config) -> Result<()> {
+    for managed in managed_domains(&config)? {
+        match managed.line_number {
+            Some(line_number) => println!(
+                "{}\t{}\t{}",
+                line_number,
+                managed.domain,
+                lock_word(!managed.is_locked)
+            ),
+            None => println!("-\t{}\tabsent", managed.domain),
+        }
+    }
+
+    Ok(())
+}
+
+fn run_format_hosts(config: Config) -> Result<()> {
+    let changed = format_hosts(&config.paths)?;
+
+    if changed.is_empty() {
+        println!("{} is already tidy", config.paths.hosts_file.display());
+    } else {
+        for line_number in changed {
+            println!("Reformatted line {}", line_number);
+        }
+        println!(
+            "The previous version was saved to {}",
+            config.paths.hosts_backup().display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Prints how the daemon would change the hosts file right now, without
+/// writing it, the state file, or running hooks.
+fn run_check(config: Config) -> Result<()> {
+    let mut state = State::read_with_config(&config).context("Unable to read state file")?;
+    state.set_dry_run();
+
+    if let Err(errors) = state.update(&config) {
+        for e in errors {
+            eprintln!("{:?}", e);
+        }
+    }
+    state.commit()?;
+
+    let hosts_file = config.paths.hosts_file.display().to_string();
+    match state.previewed_hosts() {
+        Some((current, desired)) => print!(
+            "{}",
+            unified_diff(
+                &hosts_file,
+                &format!("{} (senklot)", hosts_file),
+                current,
+                desired
+            )
+        ),
+        None => println!("{} is up to date", hosts_file),
+    }
+
+    Ok(())
+}
+
+/// Checks a config without starting anything, failing the same way `start`
+/// would.
+fn run_validate(options: ValidateOptions) -> Result<()> {
+    let path = options
+        .config
+        .as_deref()
+        .unwrap_or_else(|| Path::new(CONFIG_FILE));
+    let source = read_config_file(path)
+        .with_context(|| format!("Unable to read config {}", path.display()))?;
+    parse_config(&source).with_context(|| format!("Invalid config {}", path.display()))?;
+    println!("{} is valid", path.display());
+    Ok(())
+}
+
+fn send_request<T: DeserializeOwned>(paths: &Paths, request: &Request) -> Result<T> {
+    send_request_timeout(paths, request, None)
+}
+
+fn send_request_timeout<T: DeserializeOwned>(
+    paths: &Paths,
+    request: &Request,
+    timeout: Option<time::Duration>,
+) -> Result<T> {
+    let mut stream = net::UnixStream::connect(&paths.socket)?;
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    stream.write_all(&bincode::serialize(request)?)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    Ok(bincode::deserialize(&response)?)
+}
+
+fn main_loop(mut config: Config, mut state: State, options: StartOptions) -> Result<()> {
+    let channels = if options.foreground {
+        prepare_channels(&config.paths, !options.no_watch)?
+    } else {
+        daemonize(&config.paths, &options)?
+    };
+    let mut interval = state.tick_interval(&config);
+    let mut ticker = tick(interval.to_std().unwrap());
+    let (_watcher, hosts_modified) = match channels.hosts_modified {
+        Some((watcher, hosts_modified)) => (Some(watcher), hosts_modified),
+        None => {
+            info!("Watching {} is disabled", config.paths.hosts_file.display());
+            (None, channel::never())
+        }
+    };
+    let (_socket, request) = channels.request;
     let exit = channels.exit;
+    let reload = channels.reload;
+    let hook_exited = channels.hook_exited;
+    let blocker_refreshed = state.blocker_refreshed();
+    let mut errors = LogThrottle::new(Duration::minutes(10), Box::new(SystemClock));
+
+    if config.unlock_code {
+        state.rotate_unlock_code()?;
+    }
+    if let Err(e) = state.clean_stale_hosts() {
+        error!("{:?}", e);
+    }
+    // Apply the schedule now rather than up to one interval after startup.
+    if let Err(e) = state.update(&config) {
+        for e in e {
+            errors.log(format!("{:?}", e));
+        }
+    }
 
     loop {
         select! {
             recv(ticker) -> _ => {
+                errors.flush();
                 if let Err(e) = state.update(&config) {
                     for e in e {
-                        println!("{:?}", e);
+                        errors.log(format!("{:?}", e));
                     }
                 }
+                // Reconcile edits made by others even when nothing is due, which
+                // is the only correction when watching /etc/hosts is disabled.
+                if let Err(e) = state.commit() {
+                    errors.log(format!("{:?}", e));
+                }
             },
             recv(exit) -> _ => {
-                if let Err(e) = fs::write("/var/lib/senklot", state.export()) {
-                    println!("{:?}", e);
+                if config.unlock_on_exit || options.unlock_on_exit {
+                    if let Err(e) = state.release() {
+                        error!("{:?}", e);
+                    }
+                }
+                if let Err(e) = state.save() {
+                    error!("{:?}", e);
                 }
                 return Ok(());
             },
-            recv(hosts_modified) -> _ => {
+            recv(reload) -> _ => {
+                match reload_config(Path::new(CONFIG_FILE), &config) {
+                    Ok(None) => info!("Config unchanged, skipping reload"),
+                    Ok(Some(new_config)) => {
+                        if new_config.unlock_code && !config.unlock_code {
+                            if let Err(e) = state.rotate_unlock_code() {
+                                error!("{:?}", e);
+                            }
+                        }
+                        config = new_config;
+                        state.reconfigure(&config);
+                        info!("Reloaded config");
+
+                        if let Err(e) = state.clean_stale_hosts() {
+                            error!("{:?}", e);
+                        }
+                        if let Err(e) = state.update(&config) {
+                            for e in e {
+                                errors.log(format!("{:?}", e));
+                            }
+                        }
+                    }
+                    Err(e) => error!("Keeping the current config: {:?}", e),
+                }
+            },
+            recv(blocker_refreshed) -> _ => {
                 if let Err(e) = state.commit() {
-                    println!("{:?}", e);
+                    errors.log(format!("{:?}", e));
+                }
+            },
+            recv(hook_exited) -> _ => {
+                if let Err(e) = state.poll_hooks() {
+                    errors.log(format!("{:?}", e));
                 }
             },
-            recv(unlock_request) -> msg => {
-                if let Ok((socket, name)) = msg {
-                    if let Err(e)
-                        = state.request_unlock(socket, &name, &config.entries[&name], &config.after_unlock)
-                    {
-                        println!("{:?}", e);
+            recv(hosts_modified) -> _ => {
+                // Our own writes wake the watcher too; only reconcile others' edits.
+                if !state.hosts_are_own_write() {
+                    if let Err(e) = state.commit() {
+                        errors.log(format!("{:?}", e));
+                    }
+                }
+            },
+            recv(request) -> msg => {
+                match msg {
+                    Ok((socket, Request::Unlock(request))) => {
+                        let result = match config.entries.get(&request.name) {
+                            Some(entry) => state.request_unlock(
+                                socket,
+                                &request,
+                                entry,
+                                &config.after_unlock,
+                            ),
+                            None => {
+                                respond(socket, &UnlockResponse::unknown_entry(&request.name))
+                            }
+                        };
+                        if let Err(e) = result {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::CompleteUnlock { name, nonce, secs })) => {
+                        let result = match config.entries.get(&name) {
+                            Some(entry) => state.request_complete_unlock(
+                                socket,
+                                &name,
+                                nonce,
+                                secs.map(Duration::seconds),
+                                entry,
+                                &config.after_unlock,
+                            ),
+                            None => respond(socket, &UnlockResponse::unknown_entry(&name)),
+                        };
+                        if let Err(e) = result {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::UnlockScheduled)) => {
+                        if let Err(e) = state.request_unlock_scheduled(socket, &config) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Lock { name })) => {
+                        let result = match config.entries.get(&name) {
+                            Some(entry) => {
+                                state.request_lock(socket, &name, entry, &config.after_lock)
+                            }
+                            None => respond(
+                                socket,
+                                &LockResponse::Fail {
+                                    cause: format!("Unknown entry `{}`", name),
+                                },
+                            ),
+                        };
+                        if let Err(e) = result {
+                            error!("{:?}", e);
+                        }
                     }
+                    Ok((socket, Request::Why { name })) => match config.entries.get(&name) {
+                        Some(entry) => {
+                            if let Err(e) = state.request_why(socket, &name, entry) {
+                                error!("{:?}", e);
+                            }
+                        }
+                        None => {
+                            let response = WhyResponse {
+                                is_locked: None,
+                                reason: format!("Unknown entry `{}`", name),
+                            };
+                            if let Err(e) = respond(socket, &response) {
+                                error!("{:?}", e);
+                            }
+                        }
+                    },
+                    Ok((socket, Request::Remaining { name })) => {
+                        match config.entries.get(&name) {
+                            Some(entry) => {
+                                if let Err(e) = state.request_remaining(socket, &name, entry) {
+                                    error!("{:?}", e);
+                                }
+                            }
+                            None => {
+                                let response = RemainingResponse::Fail {
+                                    cause: format!("Unknown entry `{}`", name),
+                                };
+                                if let Err(e) = respond(socket, &response) {
+                                    error!("{:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    Ok((socket, Request::Schedule)) => {
+                        if let Err(e) = respond(socket, &Schedule::of(&config)) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Ping)) => {
+                        let response = PingResponse {
+                            version: env!("CARGO_PKG_VERSION").to_owned(),
+                        };
+                        if let Err(e) = respond(socket, &response) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::ExportHosts)) => {
+                        if let Err(e) = state.request_export_hosts(socket) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Focus { secs, tag })) => {
+                        if let Err(e) = state.request_focus(
+                            socket,
+                            Duration::seconds(secs),
+                            tag.as_deref(),
+                            &config,
+                        ) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Config)) => {
+                        let response = ConfigResponse {
+                            source: config.source.clone(),
+                        };
+                        if let Err(e) = respond(socket, &response) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Status)) => {
+                        if let Err(e) = state.request_status(socket, &config) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::Stats)) => {
+                        if let Err(e) = state.request_stats(socket) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Ok((socket, Request::FastTick { secs })) => {
+                        if let Err(e) = state.request_fast_tick(socket, Duration::seconds(secs)) {
+                            error!("{:?}", e);
+                        }
+                    }
+                    Err(_) => {}
                 }
             }
         }
+
+        let next_interval = state.tick_interval(&config);
+        if next_interval != interval {
+            interval = next_interval;
+            ticker = tick(interval.to_std().unwrap());
+        }
     }
 }
 
-fn daemonize() -> Result<Channels> {
-    fs::create_dir_all("/tmp/senklot")?;
+fn daemonize(paths: &Paths, options: &StartOptions) -> Result<Channels> {
+    fs::create_dir_all(&paths.log_dir)?;
 
-    let stdout = File::create("/tmp/senklot/stdout.log")
-        .context("Unable to open /tmp/senklot/stdout.log")?;
-    let stderr = File::create("/tmp/senklot/stderr.log")
-        .context("Unable to open /tmp/senklot/stderr.log")?;
+    let stdout = create_log(&paths.stdout_log())?;
+    let stderr = create_log(&paths.stderr_log())?;
 
-    let channels = Daemonize::new()
+    Daemonize::new()
         .stdout(stdout)
         .stderr(stderr)
-        .pid_file("/tmp/senklot/senklot.pid")
-        .privileged_action(prepare_channels)
+        .pid_file(paths.pid_file())
+        .privileged_action({
+            let watch = !options.no_watch;
+            let paths = paths.clone();
+            move || prepare_channels(&paths, watch)
+        })
         .start()
-        .context("Unable to start daemon")?;
+        .context("Unable to start daemon")?
+}
 
-    channels
+fn create_log(path: &Path) -> Result<File> {
+    create_private(path).with_context(|| format!("Unable to open {}", path.display()))
 }
 
-fn prepare_channels() -> Result<Channels> {
+fn prepare_channels(paths: &Paths, watch: bool) -> Result<Channels> {
+    let hosts_modified = if watch {
+        Some(hosts_modified_channel(&paths.hosts_file)?)
+    } else {
+        None
+    };
+
     Ok(Channels {
         exit: exit_channel()?,
-        hosts_modified: hosts_modified_channel()?,
-        unlock_request: unlock_request_channel()?,
+        reload: reload_channel()?,
+        hosts_modified,
+        request: request_channel(&paths.socket)?,
+        hook_exited: hook_exited_channel()?,
     })
 }
 
 struct Channels {
     exit: channel::Receiver<()>,
-    hosts_modified: (RecommendedWatcher, channel::Receiver<()>),
-    unlock_request: (SocketPath, channel::Receiver<(net::UnixStream, String)>),
+    reload: channel::Receiver<()>,
+    hook_exited: channel::Receiver<()>,
+    hosts_modified: Option<(RecommendedWatcher, channel::Receiver<()>)>,
+    request: (SocketPath, channel::Receiver<(net::UnixStream, Request)>),
 }
 
 fn exit_channel() -> Result<channel::Receiver<()>> {
+    signal_channel(&[SIGINT, SIGTERM, SIGQUIT])
+}
+
+fn reload_channel() -> Result<channel::Receiver<()>> {
+    signal_channel(&[SIGHUP])
+}
+
+/// Wakes the daemon when a hook exits, so a queued one can start in its place.
+fn hook_exited_channel() -> Result<channel::Receiver<()>> {
+    signal_channel(&[SIGCHLD])
+}
+
+fn signal_channel(signals: &[i32]) -> Result<channel::Receiver<()>> {
     let (tx, rx) = channel::bounded(0);
-    ctrlc::set_handler(move || {
-        let _ = tx.send(());
-    })?;
+    let signals = Signals::new(signals)?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let _ = tx.send(());
+        }
+    });
 
     Ok(rx)
 }
 
-fn hosts_modified_channel() -> Result<(RecommendedWatcher, channel::Receiver<()>)> {
-    let (tx, rx) = channel::bounded(0);
+const HOSTS_DEBOUNCE: time::Duration = time::Duration::from_millis(500);
+
+fn hosts_modified_channel(
+    hosts_file: &Path,
+) -> Result<(RecommendedWatcher, channel::Receiver<()>)> {
+    // Editors and senklot itself replace the file by renaming another over it,
+    // which ends a watch on the file, so the directory is watched instead.
+    let dir = hosts_file
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let name = hosts_file
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", hosts_file.display()))?
+        .to_owned();
+
+    let (events_tx, events_rx) = channel::unbounded();
     let mut watcher: RecommendedWatcher = Watcher::new_immediate(move |event| {
-        if let Ok(Event {
-            kind: EventKind::Modify(ModifyKind::Data(_)),
-            ..
-        }) = event
-        {
-            let _ = tx.send(());
+        if let Ok(Event { kind, paths, .. }) = event {
+            let is_change = matches!(
+                kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(ModifyKind::Data(_))
+                    | EventKind::Modify(ModifyKind::Name(_))
+            );
+            if is_change && paths.iter().any(|path| path.file_name() == Some(&name)) {
+                let _ = events_tx.send(());
+            }
         }
     })?;
-    watcher.watch("/etc/hosts", RecursiveMode::NonRecursive)?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    // Report a burst of writes once it has settled, rather than every write.
+    let (tx, rx) = channel::bounded(0);
+    thread::spawn(move || {
+        while events_rx.recv().is_ok() {
+            while events_rx.recv_timeout(HOSTS_DEBOUNCE).is_ok() {}
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
 
     Ok((watcher, rx))
 }
 
-fn unlock_request_channel() -> Result<(SocketPath, channel::Receiver<(net::UnixStream, String)>)> {
+fn request_channel(
+    socket: &Path,
+) -> Result<(SocketPath, channel::Receiver<(net::UnixStream, Request)>)> {
     let (tx, rx) = channel::bounded(0);
-    let (path, listener) = SocketPath::bind("/var/lib/senklot.socket")?;
+    let (path, listener) = SocketPath::bind(socket)?;
     path.allow_write()?;
     std::thread::spawn(move || {
-        for stream in listener.incoming() {
-            if let Ok(mut stream) = stream {
-                let mut buffer = String::new();
-                if stream.read_to_string(&mut buffer).is_ok() {
-                    let _ = tx.send((stream, buffer));
-                };
-            }
+        for mut stream in listener.incoming().flatten() {
+            let mut buffer = Vec::new();
+            if stream.read_to_end(&mut buffer).is_ok() {
+                if let Ok(request) = bincode::deserialize(&buffer) {
+                    let _ = tx.send((stream, request));
+                }
+            };
         }
     });
     Ok((path, rx))
 }
 
-fn read_config_file() -> Result<String> {
-    let config_file = "/etc/senklot/config";
-    let content = fs::read_to_string(config_file)?;
+const CONFIG_FILE: &str = "/etc/senklot/config";
+
+fn read_config_file(path: &Path) -> Result<String> {
+    let content = fs::read_to_string(path)?;
     Ok(content)
 }
 
-fn parse_config(config: &str) -> Result<Config> {
-    let config = toml::from_str(config)?;
+/// The config in `path`, or `None` if it and its holidays files still have the
+/// content `current` was parsed from, as after a touch or a no-op save.
+///
+/// Paths stay as the daemon started with them, since the socket and logs are
+/// already open.
+fn reload_config(path: &Path, current: &Config) -> Result<Option<Config>> {
+    let source = read_config_file(path).context("Unable to read config")?;
+    let mut config = parse_config_quietly(&source).context("Parse error in config")?;
+    if config.source_hash == current.source_hash {
+        return Ok(None);
+    }
+    warn_about(&config);
+    config.paths = current.paths.clone();
+    Ok(Some(config))
+}
+
+fn parse_config(source: &str) -> Result<Config> {
+    let config = parse_config_quietly(source)?;
+    warn_about(&config);
+    Ok(config)
+}
+
+fn parse_config_quietly(source: &str) -> Result<Config> {
+    check_config_version(source)?;
+    let mut config: Config = toml::from_str(source).map_err(|e| explain_parse_error(source, e))?;
+    config.normalize_domains();
+    config.validate()?;
+    config.inherit_defaults();
+    config.source = source.to_owned();
+    config.source_hash = source_hash(source, &config.entries);
     Ok(config)
 }
+
+fn warn_about(config: &Config) {
+    for warning in config.warnings() {
+        warn!("{}", warning);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use util::tests::temp_path;
+
+    #[test]
+    fn hosts_watch_survives_the_file_being_replaced() {
+        let dir = temp_path("watch");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let (hosts_file, tmp) = (dir.join("hosts"), dir.join("hosts.tmp"));
+        fs::write(&hosts_file, "127.0.0.1 localhost\n").unwrap();
+
+        let (_watcher, modified) = hosts_modified_channel(&hosts_file).unwrap();
+        for content in &["10.0.0.1 intranet\n", "10.0.0.2 intranet\n"] {
+            fs::write(&tmp, content).unwrap();
+            fs::rename(&tmp, &hosts_file).unwrap();
+            assert!(modified.recv_timeout(time::Duration::from_secs(5)).is_ok());
+        }
+
+        fs::write(dir.join("other"), "").unwrap();
+        assert!(modified.recv_timeout(HOSTS_DEBOUNCE * 4).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn json_line(message: &str) -> serde_json::Value {
+        let line = json_log_line(
+            &log::Record::builder()
+                .args(format_args!("{}", message))
+                .level(log::Level::Info)
+                .build(),
+        );
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn json_log_line_names_the_entry_being_acted_on() {
+        let line = json_line("Locked example.com");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["message"], "Locked example.com");
+        assert!(line["entry"].is_null());
+        assert!(line["timestamp"].is_string());
+
+        let _scope = LogScope::new("sns", "lock");
+        let line = json_line("Locked example.com");
+        assert_eq!(line["entry"], "sns");
+        assert_eq!(line["action"], "lock");
+    }
+
+    #[test]
+    fn reload_is_skipped_until_the_config_changes() {
+        let path = temp_path("reload.toml");
+        let source = "[sns]\ndomains = [\"example.com\"]\n";
+        fs::write(&path, source).unwrap();
+        let current = parse_config(source).unwrap();
+
+        fs::write(&path, source).unwrap();
+        assert!(reload_config(&path, &current).unwrap().is_none());
+
+        fs::write(&path, "[sns]\ndomains = [\"example.org\"]\n").unwrap();
+        let reloaded = reload_config(&path, &current).unwrap().unwrap();
+        assert_eq!(reloaded.entries["sns"].domains, ["example.org"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reload_picks_up_a_changed_holidays_file() {
+        let (path, holidays) = (temp_path("reload-holidays.toml"), temp_path("holidays"));
+        let source = format!(
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"06:00-07:00\"]\nholidays = \"{}\"\n",
+            holidays.display()
+        );
+        fs::write(&path, &source).unwrap();
+        fs::write(&holidays, "2020-12-25\n").unwrap();
+        let current = parse_config(&source).unwrap();
+        assert!(reload_config(&path, &current).unwrap().is_none());
+
+        fs::write(&holidays, "2020-12-25\n2021-01-01\n").unwrap();
+        let reloaded = reload_config(&path, &current).unwrap().unwrap();
+        assert_eq!(reloaded.entries["sns"].holidays.len(), 2);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&holidays).unwrap();
+    }
+
+    fn targets(name: Option<&str>, tag: Option<&str>) -> Targets {
+        Targets {
+            name: name.map(str::to_owned),
+            stdin: false,
+            tag: tag.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn tag_selects_every_entry_carrying_it() {
+        let config = parse_config(
+            "[video]\ndomains = [\"example.net\"]\ntags = [\"focus\", \"evening\"]\n\
+             [sns]\ndomains = [\"example.com\"]\ntags = [\"focus\"]\n\
+             [news]\ndomains = [\"example.org\"]\n",
+        )
+        .unwrap();
+
+        let mut selected = Vec::new();
+        for_each_target(&config, &targets(None, Some("focus")), |name| {
+            selected.push(name.to_owned());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(selected, ["sns", "video"]);
+
+        let e = for_each_target(&config, &targets(Some("sns"), Some("focus")), |_| Ok(()));
+        assert!(e.unwrap_err().to_string().starts_with("Specify only one"));
+    }
+
+    #[test]
+    fn simulate_lists_the_schedule_changes() {
+        let config = parse_config(
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"06:00-07:00\", \"Sat 20:00-22:00\"]\n\
+             [news]\ndomains = [\"example.org\"]\n",
+        )
+        .unwrap();
+        let start = Local.ymd(2020, 6, 5).and_hms(12, 0, 0);
+        let at = |day, hour| Local.ymd(2020, 6, day).and_hms(hour, 0, 0);
+
+        let end = start + Duration::hours(36);
+        let timeline = simulate(&config.entries["sns"], start, end, Duration::minutes(1));
+        assert_eq!(
+            timeline.unwrap(),
+            [
+                (start, false),
+                (at(6, 6), true),
+                (at(6, 7), false),
+                (at(6, 20), true),
+                (at(6, 22), false),
+            ]
+        );
+
+        assert!(simulate(&config.entries["news"], start, end, Duration::minutes(1)).is_none());
+    }
+
+    fn lock_targets(args: &[&str]) -> Targets {
+        use structopt::StructOpt;
+        let args = ["senklot", "lock"].iter().chain(args);
+        match Cli::from_iter_safe(args).unwrap().command {
+            Args::Lock(options) => options.targets,
+            _ => panic!("not a lock"),
+        }
+    }
+
+    #[test]
+    fn lock_takes_a_name_or_names_from_stdin() {
+        let targets = lock_targets(&["sns"]);
+        assert_eq!(targets.name.as_deref(), Some("sns"));
+        assert!(!targets.stdin);
+
+        let targets = lock_targets(&["--stdin"]);
+        assert_eq!(targets.name, None);
+        assert!(targets.stdin);
+    }
+
+    #[test]
+    fn lock_selects_entries_by_tag() {
+        let targets = lock_targets(&["--tag", "focus"]);
+        assert_eq!(targets.name, None);
+        assert_eq!(targets.tag.as_deref(), Some("focus"));
+    }
+
+    #[test]
+    fn only_a_daemonized_start_logs_to_stdout() {
+        let start = |foreground| {
+            Args::Start(StartOptions {
+                no_watch: false,
+                unlock_on_exit: false,
+                foreground,
+            })
+        };
+        assert!(matches!(log_target(&start(false)), Target::Stdout));
+        assert!(matches!(log_target(&start(true)), Target::Stderr));
+        assert!(matches!(log_target(&Args::Status {}), Target::Stderr));
+    }
+}
@@ -1,14 +1,257 @@
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::os::unix::net;
 
-use crate::config::LocalTime;
+use crate::config::*;
+
+#[derive(Serialize, Deserialize)]
+pub struct UnlockRequest {
+    pub name: String,
+    pub reason: Option<String>,
+    pub code: Option<String>,
+    /// Lock again after this many seconds, whatever the schedule says.
+    pub secs: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    Unlock(UnlockRequest),
+    Lock {
+        name: String,
+    },
+    UnlockScheduled,
+    Why {
+        name: String,
+    },
+    CompleteUnlock {
+        name: String,
+        nonce: u64,
+        secs: Option<i64>,
+    },
+    Remaining {
+        name: String,
+    },
+    Schedule,
+    FastTick {
+        secs: i64,
+    },
+    Ping,
+    ExportHosts,
+    Stats,
+    Config,
+    Status,
+    Focus {
+        secs: i64,
+        tag: Option<String>,
+    },
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum UnlockResponse {
     Success {
-        locked_at: LocalTime,
+        locked_at: Option<LocalTime>,
     },
     Fail {
         cause: String,
         unlocked_at: Option<LocalTime>,
     },
+    Challenge {
+        nonce: u64,
+        wait_secs: i64,
+    },
+}
+
+impl UnlockResponse {
+    pub fn unknown_entry(name: &str) -> UnlockResponse {
+        UnlockResponse::Fail {
+            cause: format!("Unknown entry `{}`", name),
+            unlocked_at: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnlockScheduledResponse {
+    /// Each unlocked entry with the reason it failed, if it did.
+    pub results: Vec<(String, Option<String>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum LockResponse {
+    Locked,
+    AlreadyLocked,
+    Fail { cause: String },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct WhyResponse {
+    pub is_locked: Option<bool>,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum RemainingResponse {
+    Unlocked {
+        locked_at: LocalTime,
+    },
+    /// Unlocked until someone locks it, as a manual entry is.
+    UnlockedIndefinitely,
+    Locked,
+    Fail {
+        cause: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PingResponse {
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportHostsResponse {
+    pub domains: Vec<ExportedDomain>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportedDomain {
+    pub domain: String,
+    pub is_locked: bool,
+    pub redirect_ip: IpAddr,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatusResponse {
+    pub entries: Vec<EntryStatus>,
+    /// When the daemon returns to its configured interval after `fast-tick`.
+    pub fast_tick_until: Option<LocalTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EntryStatus {
+    pub name: String,
+    pub is_locked: Option<bool>,
+    pub last_locked: Option<LocalTime>,
+    pub last_unlocked: Option<LocalTime>,
+    pub cool_down_secs: Option<i64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfigResponse {
+    pub source: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub rejected_unlocks: HashMap<String, u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FocusResponse {
+    pub until: LocalTime,
+    pub entries: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FastTickResponse {
+    pub until: LocalTime,
+}
+
+pub const SCHEDULE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct Schedule {
+    pub version: u32,
+    pub entries: Vec<EntrySchedule>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EntrySchedule {
+    pub name: String,
+    pub domains: Vec<String>,
+    pub restriction: RestrictionSchedule,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestrictionSchedule {
+    Static {
+        unlock: Vec<Window>,
+    },
+    Dynamic {
+        period_secs: i64,
+        cool_time_secs: i64,
+        cooldown_from: CooldownFrom,
+    },
+    Manual,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Window {
+    pub begin: String,
+    pub end: String,
+    /// Empty if the window applies every day.
+    #[serde(default)]
+    pub weekdays: Vec<String>,
+}
+
+impl Schedule {
+    pub fn of(config: &Config) -> Schedule {
+        let mut entries: Vec<_> = config
+            .entries
+            .iter()
+            .map(|(name, entry)| EntrySchedule {
+                name: name.clone(),
+                domains: entry.domains.clone(),
+                restriction: RestrictionSchedule::of(&entry.restriction),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Schedule {
+            version: SCHEDULE_VERSION,
+            entries,
+        }
+    }
+}
+
+impl RestrictionSchedule {
+    fn of(restriction: &Restriction) -> RestrictionSchedule {
+        match restriction {
+            Restriction::Static { unlock } => RestrictionSchedule::Static {
+                unlock: unlock
+                    .iter()
+                    .map(|d| Window {
+                        begin: d.begin.format("%H:%M").to_string(),
+                        end: d.end.format("%H:%M").to_string(),
+                        weekdays: d
+                            .weekdays
+                            .iter()
+                            .flatten()
+                            .map(|day| day.to_string())
+                            .collect(),
+                    })
+                    .collect(),
+            },
+            Restriction::Dynamic {
+                period,
+                cool_time,
+                cooldown_from,
+            } => RestrictionSchedule::Dynamic {
+                period_secs: period.num_seconds(),
+                cool_time_secs: cool_time.num_seconds(),
+                cooldown_from: *cooldown_from,
+            },
+            Restriction::Manual(_) => RestrictionSchedule::Manual,
+        }
+    }
+}
+
+pub fn respond<T: Serialize>(mut socket: net::UnixStream, response: &T) -> Result<()> {
+    socket.write_all(&bincode::serialize(response)?)?;
+    Ok(())
 }
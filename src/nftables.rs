@@ -0,0 +1,278 @@
+use anyhow::{anyhow, Context, Result};
+use crossbeam::channel;
+use crossbeam::channel::RecvTimeoutError;
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::prelude::*;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use crate::blocker::*;
+
+const TABLE: &str = "senklot";
+
+/// Addresses change, so domains are resolved again this often.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How soon a round in which some domain failed to resolve is retried.
+const RESOLVE_RETRY: Duration = Duration::from_secs(60);
+
+type Addrs = HashMap<String, Vec<IpAddr>>;
+
+/// Drops outbound traffic to the addresses of locked domains, which also stops
+/// programs that ignore /etc/hosts, such as browsers using DNS over HTTPS.
+///
+/// Every entry gets a pair of sets in the `inet senklot` table. A CDN or a
+/// shared host serves many sites from the same addresses, so locking a domain
+/// can block unrelated sites too; a warning names the unlocked domains hit.
+///
+/// Domains are resolved on a thread of their own, so a slow DNS server never
+/// stalls the daemon; each round is applied by the next `commit`.
+pub struct Nftables {
+    /// The entry whose sets hold each domain's addresses.
+    owners: HashMap<String, String>,
+    locked: HashMap<String, bool>,
+    addrs: Addrs,
+    resolved: channel::Receiver<Addrs>,
+    /// Dropping this stops the resolver thread.
+    _stop: channel::Sender<()>,
+    applied: Option<String>,
+}
+
+impl Nftables {
+    /// Starts resolving the domains of `owners`, sending to `refreshed` after
+    /// every round so that the caller commits the new addresses.
+    pub fn new(owners: HashMap<String, String>, refreshed: channel::Sender<()>) -> Nftables {
+        let (resolved_tx, resolved) = channel::unbounded();
+        let (stop, stop_rx) = channel::bounded(0);
+        let domains = owners.keys().cloned().collect();
+        thread::spawn(move || resolve_periodically(domains, resolved_tx, refreshed, stop_rx));
+
+        Nftables {
+            owners,
+            locked: HashMap::new(),
+            addrs: HashMap::new(),
+            resolved,
+            _stop: stop,
+            applied: None,
+        }
+    }
+
+    fn script(&self) -> String {
+        let mut sets = BTreeMap::<_, BTreeSet<IpAddr>>::new();
+        for (domain, owner) in &self.owners {
+            let set = sets.entry(owner.as_str()).or_default();
+            if self.is_locked(domain) {
+                set.extend(self.addrs.get(domain).into_iter().flatten());
+            }
+        }
+
+        // Declaring the table before deleting it makes the delete succeed on the
+        // first run, and `nft -f` applies the whole script atomically.
+        let mut script = format!(
+            "table inet {0}\ndelete table inet {0}\ntable inet {0} {{\n",
+            TABLE
+        );
+        let mut rules = String::new();
+        for (entry, addrs) in &sets {
+            let name = set_name(entry);
+            let (v4, v6): (Vec<_>, Vec<_>) = addrs.iter().partition(|addr| addr.is_ipv4());
+            script += &format!(
+                "    set {}_v4 {{ type ipv4_addr; {}}}\n",
+                name,
+                elements(&v4)
+            );
+            script += &format!(
+                "    set {}_v6 {{ type ipv6_addr; {}}}\n",
+                name,
+                elements(&v6)
+            );
+            rules += &format!(
+                "        ip daddr @{0}_v4 drop\n        ip6 daddr @{0}_v6 drop\n",
+                name
+            );
+        }
+        script +=
+            "    chain output {\n        type filter hook output priority 0; policy accept;\n";
+        script += &rules;
+        script += "    }\n}\n";
+        script
+    }
+
+    fn warn_shared_addrs(&self) {
+        let unlocked: HashMap<_, _> = self
+            .addrs
+            .iter()
+            .filter(|(domain, _)| !self.is_locked(domain))
+            .flat_map(|(domain, addrs)| addrs.iter().map(move |addr| (addr, domain)))
+            .collect();
+
+        for (domain, addrs) in &self.addrs {
+            if !self.is_locked(domain) {
+                continue;
+            }
+            for addr in addrs {
+                if let Some(other) = unlocked.get(addr) {
+                    warn!(
+                        "{} shares the address {} with {}, which is blocked too although unlocked",
+                        domain, addr, other
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Blocker for Nftables {
+    fn set_locked(&mut self, domain: &str, locked: bool) {
+        self.locked.insert(domain.to_owned(), locked);
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        // Only the latest round matters.
+        if let Some(addrs) = self.resolved.try_iter().last() {
+            self.addrs = addrs;
+        }
+        let script = self.script();
+        if self.applied.as_ref() == Some(&script) {
+            return Ok(());
+        }
+
+        self.warn_shared_addrs();
+        run_nft(&script)?;
+        info!("Updated the nftables sets");
+        self.applied = Some(script);
+        Ok(())
+    }
+
+    fn is_locked(&self, domain: &str) -> bool {
+        self.locked.get(domain).cloned().unwrap_or(false)
+    }
+
+    fn remove(&mut self) -> Result<()> {
+        run_nft(&format!("table inet {0}\ndelete table inet {0}\n", TABLE))?;
+        info!("Removed the nftables table");
+        self.applied = None;
+        Ok(())
+    }
+}
+
+fn resolve_periodically(
+    domains: Vec<String>,
+    resolved: channel::Sender<Addrs>,
+    refreshed: channel::Sender<()>,
+    stop: channel::Receiver<()>,
+) {
+    let mut addrs = Addrs::new();
+    loop {
+        let mut failed = false;
+        for domain in &domains {
+            // A domain that fails to resolve keeps the addresses it had last.
+            match (domain.as_str(), 0).to_socket_addrs() {
+                Ok(found) => {
+                    let mut found: Vec<_> = found.map(|addr| addr.ip()).collect();
+                    found.sort();
+                    found.dedup();
+                    addrs.insert(domain.clone(), found);
+                }
+                Err(e) => {
+                    warn!("Unable to resolve {}: {}", domain, e);
+                    failed = true;
+                }
+            }
+        }
+
+        if resolved.send(addrs.clone()).is_err() {
+            return;
+        }
+        let _ = refreshed.try_send(());
+
+        let wait = if failed {
+            RESOLVE_RETRY
+        } else {
+            RESOLVE_INTERVAL
+        };
+        if stop.recv_timeout(wait) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    }
+}
+
+/// nft identifiers are limited to letters, digits and underscores.
+fn set_name(entry: &str) -> String {
+    let entry: String = entry
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("entry_{}", entry)
+}
+
+fn elements(addrs: &[&IpAddr]) -> String {
+    if addrs.is_empty() {
+        return String::new();
+    }
+    let addrs: Vec<_> = addrs.iter().map(|addr| addr.to_string()).collect();
+    format!("elements = {{ {} }}; ", addrs.join(", "))
+}
+
+fn run_nft(script: &str) -> Result<()> {
+    let mut nft = process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .context("Unable to run nft")?;
+    if let Some(mut stdin) = nft.stdin.take() {
+        stdin.write_all(script.as_bytes())?;
+    }
+
+    let output = nft.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nftables(owners: &[(&str, &str)]) -> (Nftables, channel::Receiver<()>) {
+        let (refreshed, refreshed_rx) = channel::bounded(1);
+        let owners = owners
+            .iter()
+            .map(|&(domain, owner)| (domain.to_owned(), owner.to_owned()))
+            .collect();
+        (Nftables::new(owners, refreshed), refreshed_rx)
+    }
+
+    #[test]
+    fn only_locked_domains_fill_their_entry_sets() {
+        let (mut nft, _) = nftables(&[("a.invalid", "sns"), ("b.invalid", "news")]);
+        nft.addrs
+            .insert("a.invalid".to_owned(), vec!["192.0.2.1".parse().unwrap()]);
+        nft.addrs
+            .insert("b.invalid".to_owned(), vec!["2001:db8::1".parse().unwrap()]);
+        nft.set_locked("a.invalid", true);
+        nft.set_locked("b.invalid", false);
+
+        let script = nft.script();
+        assert!(script.contains("set entry_sns_v4 { type ipv4_addr; elements = { 192.0.2.1 }; }"));
+        assert!(script.contains("set entry_news_v6 { type ipv6_addr; }"));
+        assert!(script.contains("ip daddr @entry_sns_v4 drop"));
+    }
+
+    #[test]
+    fn domains_are_resolved_in_the_background() {
+        let (nft, refreshed) = nftables(&[("localhost", "local")]);
+        assert!(refreshed.recv_timeout(Duration::from_secs(10)).is_ok());
+
+        let addrs = nft.resolved.try_iter().last().unwrap();
+        assert!(addrs["localhost"].iter().any(|addr| addr.is_loopback()));
+    }
+}
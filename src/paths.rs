@@ -0,0 +1,136 @@
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Every file senklot touches outside its config, so it can run against a
+/// sandbox instead of the system paths.
+#[derive(Clone, Deserialize)]
+pub struct Paths {
+    #[serde(default = "default_hosts_file")]
+    pub hosts_file: PathBuf,
+    #[serde(default = "default_state_file")]
+    pub state_file: PathBuf,
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    #[serde(default = "default_log_dir")]
+    pub log_dir: PathBuf,
+    /// Written instead of the hosts file with `backend = "dnsmasq"`.
+    #[serde(default = "default_dnsmasq_conf")]
+    pub dnsmasq_conf: PathBuf,
+}
+
+impl Default for Paths {
+    fn default() -> Paths {
+        Paths {
+            hosts_file: default_hosts_file(),
+            state_file: default_state_file(),
+            socket: default_socket(),
+            log_dir: default_log_dir(),
+            dnsmasq_conf: default_dnsmasq_conf(),
+        }
+    }
+}
+
+impl Paths {
+    pub fn pid_file(&self) -> PathBuf {
+        self.log_dir.join("senklot.pid")
+    }
+
+    pub fn stdout_log(&self) -> PathBuf {
+        self.log_dir.join("stdout.log")
+    }
+
+    pub fn stderr_log(&self) -> PathBuf {
+        self.log_dir.join("stderr.log")
+    }
+
+    /// The current unlock code, readable only by the daemon's user.
+    pub fn unlock_code_file(&self) -> PathBuf {
+        with_suffix(&self.state_file, ".unlock-code")
+    }
+
+    pub fn hosts_tmp(&self) -> PathBuf {
+        with_suffix(&self.hosts_file, ".senklot.tmp")
+    }
+
+    pub fn hosts_backup(&self) -> PathBuf {
+        with_suffix(&self.hosts_file, ".senklot.bak")
+    }
+
+    /// Hidden, as dnsmasq's `conf-dir` skips dotfiles and must not load a
+    /// half-written fragment next to `dnsmasq_conf`.
+    pub fn dnsmasq_tmp(&self) -> PathBuf {
+        let name = self.dnsmasq_conf.file_name().unwrap_or_default();
+        let mut tmp = OsString::from(".");
+        tmp.push(name);
+        tmp.push(".tmp");
+        self.dnsmasq_conf.with_file_name(tmp)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn default_hosts_file() -> PathBuf {
+    PathBuf::from("/etc/hosts")
+}
+
+/// Created by systemd's `RuntimeDirectory=senklot` and `StateDirectory=senklot`.
+/// Clients look for the directories themselves rather than reading
+/// `$RUNTIME_DIRECTORY` and `$STATE_DIRECTORY`, which only the daemon's unit
+/// sets, so that both sides agree on the socket and state file.
+///
+/// `--socket` and `--state-file` take precedence over `[paths]` in the config,
+/// which takes precedence over these directories and then the `/var/lib` paths.
+const RUNTIME_DIRECTORY: &str = "/run/senklot";
+const STATE_DIRECTORY: &str = "/var/lib/senklot";
+
+fn default_state_file() -> PathBuf {
+    in_directory(Path::new(STATE_DIRECTORY), "state")
+        .unwrap_or_else(|| PathBuf::from("/var/lib/senklot"))
+}
+
+fn default_socket() -> PathBuf {
+    in_directory(Path::new(RUNTIME_DIRECTORY), "senklot.socket")
+        .unwrap_or_else(|| PathBuf::from("/var/lib/senklot.socket"))
+}
+
+fn default_log_dir() -> PathBuf {
+    PathBuf::from("/tmp/senklot")
+}
+
+fn default_dnsmasq_conf() -> PathBuf {
+    PathBuf::from("/etc/dnsmasq.d/senklot.conf")
+}
+
+/// `dir/name` if `dir` is a directory. The state file of older versions is a
+/// plain file at `STATE_DIRECTORY` itself, which is kept where it is.
+fn in_directory(dir: &Path, name: &str) -> Option<PathBuf> {
+    Some(dir.join(name)).filter(|_| dir.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn paths_go_in_the_directory_only_once_it_exists() {
+        let dir = env::temp_dir().join(format!("senklot-test-{}-paths", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(in_directory(&dir, "state"), None);
+
+        fs::write(&dir, "legacy state").unwrap();
+        assert_eq!(in_directory(&dir, "state"), None);
+        fs::remove_file(&dir).unwrap();
+
+        fs::create_dir(&dir).unwrap();
+        assert_eq!(in_directory(&dir, "state"), Some(dir.join("state")));
+        fs::remove_dir(&dir).unwrap();
+    }
+}
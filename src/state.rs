@@ -1,32 +1,188 @@
-use anyhow::{anyhow, Result};
-use chrono::offset::Local;
+use anyhow::{anyhow, Context, Result};
+use chrono::Duration;
+use crossbeam::channel;
+use log::{error, info, warn};
 use nom::character::complete::{none_of, space0, space1};
-use nom::{alt, many1, map, named, recognize, tag, tuple};
+use nom::combinator::rest;
+use nom::{alt, complete, many1, map, named, opt, recognize, tag, tuple};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
-use std::io::prelude::*;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr};
 use std::os::unix::net;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time;
 
+use crate::blocker::*;
+use crate::clock::*;
 use crate::config::*;
+use crate::dnsmasq::*;
 use crate::message::*;
+use crate::nftables::*;
+use crate::paths::*;
 use crate::util::*;
 
 #[derive(Deserialize, Serialize)]
 pub struct State {
+    #[serde(with = "utc_time::map")]
     last_unlocked: HashMap<String, LocalTime>,
+    #[serde(with = "utc_time::map")]
     last_locked: HashMap<String, LocalTime>,
     is_locked: HashMap<String, bool>,
+    #[serde(with = "utc_time::option")]
+    fast_tick_until: Option<LocalTime>,
+    #[serde(with = "utc_time::map")]
+    unlocked_since: HashMap<String, LocalTime>,
+    focus: Option<FocusSession>,
+    #[serde(default)]
+    rejected_unlocks: HashMap<String, u32>,
+    #[serde(with = "utc_time::map")]
+    locked_early: HashMap<String, LocalTime>,
+    #[serde(with = "utc_time::map")]
+    timed_unlocks: HashMap<String, LocalTime>,
     #[serde(skip)]
-    domain_map: HashMap<String, String>,
+    domain_map: HashMap<String, Vec<String>>,
     #[serde(skip)]
-    path: PathBuf,
+    redirect_map: HashMap<String, IpAddr>,
+    #[serde(skip, default = "default_redirect")]
+    default_redirect: IpAddr,
+    #[serde(skip)]
+    redirect_v6: Option<Ipv6Addr>,
+    #[serde(skip)]
+    paths: Paths,
+    #[serde(skip)]
+    max_hosts_lines: usize,
+    #[serde(skip)]
+    verify_hosts_before_write: bool,
+    #[serde(skip)]
+    flush_dns_command: Option<String>,
+    #[serde(skip, default = "system_clock")]
+    clock: Box<dyn Clock>,
+    #[serde(skip)]
+    unlock_code_hash: Option<u64>,
+    /// Wrong unlock codes since the last right one; each doubles the wait
+    /// before the next attempt, so the code cannot be guessed by brute force.
+    #[serde(skip)]
+    wrong_codes: u32,
+    #[serde(skip)]
+    code_retry_at: Option<LocalTime>,
+    #[serde(skip)]
+    challenges: HashMap<String, (u64, LocalTime)>,
+    #[serde(skip)]
+    hosts_backoff: Option<(LocalTime, Duration)>,
+    /// A write was deferred by `hosts_backoff` and is retried once it expires.
+    #[serde(skip)]
+    pending_write: bool,
+    /// Transitions whose hooks wait for a deferred or failed write to land.
+    #[serde(skip)]
+    pending_transitions: Vec<(String, bool)>,
+    #[serde(skip)]
+    last_nagged: HashMap<String, LocalTime>,
+    #[serde(skip)]
+    vetoes: HashMap<String, u32>,
+    #[serde(skip)]
+    deferred_locks: HashMap<String, LocalTime>,
+    #[serde(skip)]
+    hooks: HookPool,
+    #[serde(skip)]
+    written_hosts: Option<String>,
+    #[serde(skip)]
+    dry_run: bool,
+    #[serde(skip)]
+    previewed_hosts: Option<(String, String)>,
+    /// Enforces the locks of the domains using each backend other than the
+    /// hosts file, whose lines for those domains are released.
+    #[serde(skip)]
+    blockers: BTreeMap<Backend, Box<dyn Blocker>>,
+    #[serde(skip)]
+    domain_backends: HashMap<String, Backend>,
+    /// Woken by a blocker whose enforcement changed on its own, such as
+    /// nftables after resolving its domains again.
+    #[serde(skip, default = "blocker_refreshed")]
+    blocker_refreshed: (channel::Sender<()>, channel::Receiver<()>),
+}
+
+#[derive(Deserialize, Serialize)]
+struct FocusSession {
+    #[serde(with = "utc_time")]
+    until: LocalTime,
+    entries: Vec<String>,
+}
+
+fn system_clock() -> Box<dyn Clock> {
+    Box::new(SystemClock)
+}
+
+fn blocker_refreshed() -> (channel::Sender<()>, channel::Receiver<()>) {
+    channel::bounded(1)
+}
+
+fn fast_tick_interval() -> Duration {
+    Duration::seconds(5)
+}
+
+fn min_hosts_backoff() -> Duration {
+    Duration::minutes(1)
 }
 
-fn read_state_file(path: &str) -> Result<Option<Vec<u8>>> {
-    let path = Path::new(path);
+fn max_hosts_backoff() -> Duration {
+    Duration::hours(1)
+}
+
+fn max_code_wait() -> Duration {
+    Duration::hours(1)
+}
+
+/// Starts every state file since the first release, whose files held only
+/// `LegacyState`. bincode is not self-describing, so `STATE_VERSION` must be
+/// bumped whenever the serialized fields of `State` change.
+const STATE_MAGIC: &[u8] = b"senklot-state";
+const STATE_VERSION: u32 = 1;
+
+/// The state file of the first release.
+#[derive(Deserialize)]
+struct LegacyState {
+    #[serde(with = "utc_time::map")]
+    last_unlocked: HashMap<String, LocalTime>,
+    #[serde(with = "utc_time::map")]
+    last_locked: HashMap<String, LocalTime>,
+    is_locked: HashMap<String, bool>,
+}
+
+impl From<LegacyState> for State {
+    fn from(legacy: LegacyState) -> State {
+        State {
+            last_unlocked: legacy.last_unlocked,
+            last_locked: legacy.last_locked,
+            is_locked: legacy.is_locked,
+            ..State::empty()
+        }
+    }
+}
+
+fn parse_state(content: &[u8]) -> Result<State> {
+    let content = match content.strip_prefix(STATE_MAGIC) {
+        Some(content) => content,
+        None => return Ok(bincode::deserialize::<LegacyState>(content)?.into()),
+    };
+
+    let (version, state) = content.split_at(content.len().min(4));
+    match bincode::deserialize(version)? {
+        STATE_VERSION => Ok(bincode::deserialize(state)?),
+        version => Err(anyhow!(
+            "version {} is unknown, was it written by a newer senklot?",
+            version
+        )),
+    }
+}
+
+fn read_state_file(path: &Path) -> Result<Option<Vec<u8>>> {
     if path.is_file() {
         let content = fs::read(path)?;
         Ok(Some(content))
@@ -36,335 +192,3255 @@ fn read_state_file(path: &str) -> Result<Option<Vec<u8>>> {
 }
 
 impl State {
-    pub fn read_with_config(config: &Config, path: &str) -> Result<State> {
+    pub fn read_with_config(config: &Config) -> Result<State> {
+        let previous_state = {
+            let previous_state = read_state_file(&config.paths.state_file)?;
+
+            match previous_state {
+                Some(state) => parse_state(&state).unwrap_or_else(|e| {
+                    warn!(
+                        "Discarding the unreadable state file {}: {}",
+                        config.paths.state_file.display(),
+                        e
+                    );
+                    State::empty()
+                }),
+                None => State::empty(),
+            }
+        };
+
+        let mut state = State {
+            paths: config.paths.clone(),
+            ..previous_state
+        };
+        state.reconfigure(config);
+
+        Ok(state)
+    }
+
+    /// Applies the settings of a (re)loaded config, keeping the timings.
+    pub fn reconfigure(&mut self, config: &Config) {
         let domain_map = {
-            let mut domain_map = HashMap::new();
+            let mut domain_map = HashMap::<_, Vec<_>>::new();
 
             for (name, entry) in &config.entries {
                 for domain in &entry.domains {
-                    domain_map.insert(domain.clone(), name.clone());
+                    domain_map
+                        .entry(domain.clone())
+                        .or_default()
+                        .push(name.clone());
                 }
             }
             domain_map
         };
 
-        let previous_state = {
-            let previous_state = read_state_file(path)?;
+        let redirect_map = {
+            let mut redirect_map = HashMap::new();
 
-            match previous_state {
-                Some(state) => bincode::deserialize(&state).unwrap_or(State::empty()),
-                None => State::empty(),
+            for entry in config.entries.values() {
+                if let Some(redirect_ip) = entry.redirect_ip {
+                    for domain in &entry.domains {
+                        redirect_map.insert(domain.clone(), redirect_ip);
+                    }
+                }
             }
+            redirect_map
         };
 
-        Ok(State {
-            domain_map: domain_map,
-            path: path.to_owned().into(),
-            ..previous_state
-        })
+        self.domain_map = domain_map;
+        self.redirect_map = redirect_map;
+        self.default_redirect = config.redirect;
+        self.redirect_v6 = Some(config.redirect_v6).filter(|_| config.block_ipv6);
+        self.max_hosts_lines = config.max_hosts_lines;
+        self.verify_hosts_before_write = config.verify_hosts_before_write;
+        self.flush_dns_command =
+            Some(config.flush_dns_command.clone()).filter(|_| config.flush_dns);
+        if !config.unlock_code {
+            self.unlock_code_hash = None;
+        }
+        self.hooks.set_max(config.max_concurrent_hooks);
+
+        // A domain shared by several entries follows the backend of its owner.
+        self.domain_backends = self
+            .domain_map
+            .keys()
+            .map(|domain| {
+                let entry = &config.entries[self.owner_of(domain)];
+                (domain.clone(), entry.backend.unwrap_or(config.backend))
+            })
+            .collect();
+
+        let mut blockers = BTreeMap::new();
+        for backend in self.domain_backends.values().copied() {
+            if blockers.contains_key(&backend) {
+                continue;
+            }
+            if let Some(blocker) = self.new_blocker(backend, config) {
+                blockers.insert(backend, blocker);
+            }
+        }
+        let previous = mem::replace(&mut self.blockers, blockers);
+        for (backend, mut blocker) in previous {
+            if self.blockers.contains_key(&backend) || self.dry_run {
+                continue;
+            }
+            if let Err(e) = blocker.remove() {
+                error!("Unable to remove the {:?} backend: {:?}", backend, e);
+            }
+        }
+    }
+
+    fn new_blocker(&self, backend: Backend, config: &Config) -> Option<Box<dyn Blocker>> {
+        match backend {
+            Backend::Hosts => None,
+            Backend::Nftables => {
+                let owners = self
+                    .domain_map
+                    .keys()
+                    .filter(|domain| self.backend_of(domain) == backend)
+                    .map(|domain| (domain.clone(), self.owner_of(domain).to_owned()))
+                    .collect();
+                Some(Box::new(Nftables::new(
+                    owners,
+                    self.blocker_refreshed.0.clone(),
+                )))
+            }
+            Backend::Dnsmasq => Some(Box::new(Dnsmasq::new(
+                self.paths.dnsmasq_conf.clone(),
+                self.paths.dnsmasq_tmp(),
+                config.dnsmasq_reload_command.clone(),
+            ))),
+        }
     }
 
     fn empty() -> State {
         State {
             domain_map: HashMap::new(),
+            redirect_map: HashMap::new(),
+            default_redirect: default_redirect(),
+            redirect_v6: None,
             last_unlocked: HashMap::new(),
             last_locked: HashMap::new(),
             is_locked: HashMap::new(),
-            path: PathBuf::new(),
+            fast_tick_until: None,
+            unlocked_since: HashMap::new(),
+            focus: None,
+            rejected_unlocks: HashMap::new(),
+            locked_early: HashMap::new(),
+            timed_unlocks: HashMap::new(),
+            paths: Paths::default(),
+            max_hosts_lines: default_max_hosts_lines(),
+            verify_hosts_before_write: default_verify_hosts_before_write(),
+            flush_dns_command: None,
+            clock: system_clock(),
+            unlock_code_hash: None,
+            wrong_codes: 0,
+            code_retry_at: None,
+            challenges: HashMap::new(),
+            hosts_backoff: None,
+            pending_write: false,
+            pending_transitions: Vec::new(),
+            last_nagged: HashMap::new(),
+            vetoes: HashMap::new(),
+            deferred_locks: HashMap::new(),
+            hooks: HookPool::default(),
+            written_hosts: None,
+            dry_run: false,
+            previewed_hosts: None,
+            blockers: BTreeMap::new(),
+            domain_backends: HashMap::new(),
+            blocker_refreshed: blocker_refreshed(),
         }
     }
 
     pub fn export(&self) -> Vec<u8> {
-        bincode::serialize(&self).unwrap()
+        let mut content = STATE_MAGIC.to_vec();
+        content.extend(bincode::serialize(&STATE_VERSION).unwrap());
+        content.extend(bincode::serialize(&self).unwrap());
+        content
     }
 
     pub fn request_unlock(
         &mut self,
-        mut socket: net::UnixStream,
-        name: &str,
+        socket: net::UnixStream,
+        request: &UnlockRequest,
         entry: &Entry,
         after_unlock: &Option<String>,
     ) -> Result<()> {
-        let result = self.unlock(name, entry, after_unlock);
+        let name = &request.name;
+        if let Err(e) = self.check_unlock_code(request.code.as_deref()) {
+            return self.respond_unlock(socket, name, entry, Err(e));
+        }
 
-        let response = {
-            let response = match result {
-                Ok(()) => UnlockResponse::Success {
-                    locked_at: self.lock_time_of(name, entry),
-                },
-                Err(e) => UnlockResponse::Fail {
-                    unlocked_at: self.unlock_time_of(name, entry),
-                    cause: format!("{:?}", e),
-                },
-            };
+        match request
+            .reason
+            .as_deref()
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty())
+        {
+            Some(reason) => info!("Unlock of `{}` requested: {}", name, reason),
+            None if entry.require_reason => {
+                let result = Err(anyhow!("A reason is required to unlock this entry"));
+                return self.respond_unlock(socket, name, entry, result);
+            }
+            None => {}
+        }
 
-            bincode::serialize(&response)?
-        };
+        if let Some(unlock_delay) = entry.unlock_delay {
+            if let Some(response) = self.issue_challenge(name, entry, unlock_delay)? {
+                return respond(socket, &response);
+            }
+        }
+
+        let duration = request.secs.map(Duration::seconds);
+        let result = self.unlock_for(name, duration, entry, after_unlock);
+        self.respond_unlock(socket, name, entry, result)
+    }
 
-        socket.write_all(&response)?;
+    /// Unlocks `name`, relocking it after `duration` whatever its schedule says.
+    fn unlock_for(
+        &mut self,
+        name: &str,
+        duration: Option<Duration>,
+        entry: &Entry,
+        after_unlock: &Option<String>,
+    ) -> Result<()> {
+        self.unlock(name, entry, after_unlock)?;
+        if let Some(duration) = duration {
+            self.timed_unlocks.set(name, self.clock.now() + duration);
+            self.save()?;
+        }
 
         Ok(())
     }
 
-    fn lock_time_of(&self, name: &str, entry: &Entry) -> LocalTime {
-        let now = Local::now();
-
-        match &entry.restriction {
-            Restriction::Static { unlock } => {
-                let currently_unlocking = unlock.iter().find(|d| d.contains(&now)).unwrap();
-                let end = currently_unlocking.end;
+    /// Replaces the unlock code with a fresh one, written to `unlock_code_file`
+    /// so that no log filter can hide it.
+    pub fn rotate_unlock_code(&mut self) -> Result<()> {
+        let code = format!("{:08}", random_u64()? % 100_000_000);
+        let path = self.paths.unlock_code_file();
+        write_private(&path, format!("{}\n", code).as_bytes())
+            .with_context(|| format!("Unable to write the unlock code to {}", path.display()))?;
+        info!("Unlock code: {}", code);
+        self.unlock_code_hash = Some(hash_code(&code));
 
-                if now.time() <= end {
-                    Local::today().and_time(end)
-                } else {
-                    Local::today().succ().and_time(end)
-                }
-                .unwrap()
-            }
-            Restriction::Dynamic { period, .. } => self.last_unlocked[name] + period.clone(),
-        }
+        Ok(())
     }
 
-    fn unlock_time_of(&self, name: &str, entry: &Entry) -> Option<LocalTime> {
-        if self.is_locked.get(name).or_if(|is_locked| !is_locked) {
-            return None;
+    fn check_unlock_code(&mut self, code: Option<&str>) -> Result<()> {
+        let expected = match self.unlock_code_hash {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        let now = self.clock.now();
+        if let Some(retry_at) = self.code_retry_at.filter(|retry_at| now < *retry_at) {
+            return Err(anyhow!(
+                "Too many wrong unlock codes; try again in {}",
+                format_duration(retry_at - now)
+            ));
         }
 
-        match entry.restriction {
-            Restriction::Static { .. } => None,
-            Restriction::Dynamic { cool_time, .. } => self
-                .last_locked
-                .get(name)
-                .map(|last_locked| *last_locked + cool_time),
+        if code.map(|code| hash_code(code.trim())) == Some(expected) {
+            self.wrong_codes = 0;
+            self.code_retry_at = None;
+            return Ok(());
         }
+
+        self.wrong_codes += 1;
+        let wait = Duration::seconds(1 << self.wrong_codes.min(12)).min(max_code_wait());
+        self.code_retry_at = Some(now + wait);
+        Err(anyhow!(
+            "Wrong unlock code; the current one is in {}",
+            self.paths.unlock_code_file().display()
+        ))
     }
 
-    pub fn unlock(
+    pub fn request_complete_unlock(
         &mut self,
+        socket: net::UnixStream,
         name: &str,
+        nonce: u64,
+        duration: Option<Duration>,
         entry: &Entry,
         after_unlock: &Option<String>,
     ) -> Result<()> {
-        if self.is_locked.get(name).and_if(|is_locked| !is_locked) {
-            return Ok(());
-        }
-        if let Restriction::Dynamic { cool_time, .. } = entry.restriction {
-            if self
-                .last_unlocked
-                .get(name)
-                .and_if(|last_unlocked| Local::now() < *last_unlocked + cool_time.clone())
-            {
-                return Err(anyhow!("Not have been cool down yet"));
+        let result = match (self.challenges.remove(name), entry.unlock_delay) {
+            (Some((expected, issued_at)), Some(unlock_delay)) if expected == nonce => {
+                if self.clock.now() < issued_at + unlock_delay {
+                    Err(anyhow!("Challenge was completed too early"))
+                } else {
+                    self.unlock_for(name, duration, entry, after_unlock)
+                }
             }
-        }
+            _ => Err(anyhow!("No matching unlock challenge")),
+        };
 
-        self.is_locked.set(name, false);
+        self.respond_unlock(socket, name, entry, result)
+    }
 
-        if matches!(entry.restriction, Restriction::Dynamic{..}) {
-            self.last_unlocked.set(name, Local::now());
+    fn issue_challenge(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        unlock_delay: Duration,
+    ) -> Result<Option<UnlockResponse>> {
+        if self.is_locked.get(name).and_if(|is_locked| !is_locked) {
+            return Ok(None);
         }
-
-        self.commit()?;
-
-        if let Some(cmd) = after_unlock {
-            excute_command(cmd, name)?;
+        if let Err(e) = self.check_cool_down(name, entry) {
+            self.count_rejected_unlock(name);
+            return Ok(Some(UnlockResponse::Fail {
+                unlocked_at: self.unlock_time_of(name, entry),
+                cause: format!("{:?}", e),
+            }));
         }
 
-        Ok(())
-    }
-
-    pub fn lock(&mut self, name: &str, entry: &Entry, after_lock: &Option<String>) -> Result<()> {
-        if self.is_locked.get(name).and_if(|is_locked| *is_locked) {
-            return Ok(());
-        }
+        let nonce = random_u64()?;
+        self.challenges
+            .insert(name.to_owned(), (nonce, self.clock.now()));
 
-        self.is_locked.set(name, true);
+        Ok(Some(UnlockResponse::Challenge {
+            nonce,
+            wait_secs: unlock_delay.num_seconds(),
+        }))
+    }
 
-        if matches!(entry.restriction, Restriction::Dynamic{..}) {
-            self.last_locked.set(name, Local::now());
-        }
+    fn respond_unlock(
+        &mut self,
+        socket: net::UnixStream,
+        name: &str,
+        entry: &Entry,
+        result: Result<()>,
+    ) -> Result<()> {
+        let response = match result {
+            Ok(()) => UnlockResponse::Success {
+                locked_at: self.lock_time_of(name, entry),
+            },
+            Err(e) => {
+                self.count_rejected_unlock(name);
+                UnlockResponse::Fail {
+                    unlocked_at: self.unlock_time_of(name, entry),
+                    cause: format!("{:?}", e),
+                }
+            }
+        };
 
-        self.commit()?;
+        respond(socket, &response)
+    }
 
-        if let Some(cmd) = after_lock {
-            excute_command(cmd, name)?;
-        }
+    pub fn request_status(&self, socket: net::UnixStream, config: &Config) -> Result<()> {
+        let now = self.clock.now();
+        let mut entries: Vec<_> = config
+            .entries
+            .iter()
+            .map(|(name, entry)| EntryStatus {
+                name: name.clone(),
+                is_locked: self.is_locked.get(name).copied(),
+                last_locked: self.last_locked.get(name).copied(),
+                last_unlocked: self.last_unlocked.get(name).copied(),
+                cool_down_secs: self
+                    .unlock_time_of(name, entry)
+                    .filter(|unlocked_at| now < *unlocked_at)
+                    .map(|unlocked_at| unlocked_at.signed_duration_since(now).num_seconds()),
+                tags: entry.tags.clone(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
 
-        Ok(())
+        respond(
+            socket,
+            &StatusResponse {
+                entries,
+                fast_tick_until: self.fast_tick_until.filter(|until| now < *until),
+            },
+        )
     }
 
-    fn domanin_is_locked(&self, domain: &str) -> bool {
-        self.domain_map
-            .get(domain)
-            .and_if_flat(|entry| self.is_locked.get(entry).cloned())
+    fn count_rejected_unlock(&mut self, name: &str) {
+        *self.rejected_unlocks.entry(name.to_owned()).or_insert(0) += 1;
     }
 
-    fn save(&self) -> Result<()> {
-        fs::write(&self.path, self.export())?;
-        Ok(())
+    pub fn request_stats(&self, socket: net::UnixStream) -> Result<()> {
+        respond(
+            socket,
+            &StatsResponse {
+                rejected_unlocks: self.rejected_unlocks.clone(),
+            },
+        )
     }
 
-    pub fn commit(&self) -> Result<()> {
-        let (hosts, state_is_changed) = {
-            let hosts = read_hosts()?;
-            let mut hosts = Hosts::parse(hosts);
-            let mut state_is_changed = false;
+    pub fn request_fast_tick(&mut self, socket: net::UnixStream, duration: Duration) -> Result<()> {
+        let until = self.clock.now() + duration;
+        self.fast_tick_until = Some(until);
 
-            for domain in self.domain_map.keys() {
-                let lock_state = self.domanin_is_locked(domain);
-
-                if lock_state != hosts.is_locked(domain) {
-                    state_is_changed = true;
-                    hosts.write_state(domain, lock_state);
-                }
-            }
-            (hosts, state_is_changed)
-        };
+        respond(socket, &FastTickResponse { until })
+    }
 
-        if !state_is_changed {
-            return Ok(());
+    pub fn tick_interval(&self, config: &Config) -> Duration {
+        if self.fast_tick_until.is_some() {
+            config.interval.min(fast_tick_interval())
+        } else {
+            config.interval
         }
-
-        hosts.save()?;
-        self.save()?;
-
-        Ok(())
     }
 
-    pub fn update(&mut self, config: &Config) -> Result<(), Vec<anyhow::Error>> {
-        let mut errors = Vec::new();
+    pub fn request_focus(
+        &mut self,
+        socket: net::UnixStream,
+        duration: Duration,
+        tag: Option<&str>,
+        config: &Config,
+    ) -> Result<()> {
+        let until = self.clock.now() + duration;
+        let mut entries: Vec<_> = config
+            .entries
+            .iter()
+            .filter(|(_, entry)| tag.or_if(|tag| entry.tags.iter().any(|t| t == tag)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        entries.sort();
 
-        let now: LocalTime = Local::now();
-        for (name, entry) in &config.entries {
-            match &entry.restriction {
-                Restriction::Static { unlock } => {
-                    if unlock.iter().any(|d| d.contains(&now)) {
-                        self.unlock(&name, &entry, &config.after_unlock)
-                            .err()
-                            .map(|e| {
-                                errors.push(e);
-                            });
-                    } else {
-                        self.lock(&name, &entry, &config.after_lock).err().map(|e| {
-                            errors.push(e);
-                        });
-                    }
-                }
-                Restriction::Dynamic { period, .. } => {
-                    if self
-                        .last_unlocked
-                        .get(name)
-                        .or_if(|last_unlocked| now < *last_unlocked + period.clone())
-                    {
-                        self.unlock(&name, &entry, &config.after_unlock)
-                            .err()
-                            .map(|e| {
-                                errors.push(e);
-                            });
-                    } else {
-                        self.lock(&name, &entry, &config.after_lock).err().map(|e| {
-                            errors.push(e);
-                        });
-                    }
-                }
+        self.focus = Some(FocusSession {
+            until,
+            entries: entries.clone(),
+        });
+        self.save()?;
+        if let Err(errors) = self.update(config) {
+            for e in errors {
+                error!("{:?}", e);
             }
         }
 
-        if errors.is_empty() {
-            Ok(())
-        } else {
-            Err(errors)
-        }
+        respond(socket, &FocusResponse { until, entries })
     }
-}
 
-named!(addr_domain(&str) -> String,
-    map!(recognize!(many1!(none_of("\t #"))), |s| s.to_owned())
-);
-named!(comment_out(&str) -> (String, Host),
-    map!(tuple!(space0, tag!("#"), locked_host), |(_, _, (domain, _))| (domain, Host::CommentedOut))
-);
-named!(locked_host(&str) -> (String, Host),
-    map!(tuple!(space0, addr_domain, space1, addr_domain), |(_, _, _, domain)| (domain, Host::Locked))
-);
-named!(host(&str) -> (String, Host),
-    alt!( locked_host
-        | comment_out
-        )
-);
+    fn focus_until(&self, name: &str) -> Option<LocalTime> {
+        self.focus
+            .as_ref()
+            .filter(|focus| focus.entries.iter().any(|entry| entry == name))
+            .map(|focus| focus.until)
+    }
 
-enum Host {
-    Locked,
-    CommentedOut,
-}
+    pub fn request_export_hosts(&self, socket: net::UnixStream) -> Result<()> {
+        let mut domains: Vec<_> = self
+            .domain_map
+            .keys()
+            .map(|domain| ExportedDomain {
+                domain: domain.clone(),
+                is_locked: self.domanin_is_locked(domain),
+                redirect_ip: self.redirect_of(domain),
+            })
+            .collect();
+        domains.sort_by(|a, b| a.domain.cmp(&b.domain));
 
-struct Hosts {
-    hosts_file: Vec<String>,
-    hosts: HashMap<String, (usize, Host)>,
-}
+        respond(socket, &ExportHostsResponse { domains })
+    }
 
-impl Hosts {
-    fn parse(hosts_file: String) -> Hosts {
-        let mut hosts = HashMap::new();
-        for (line_number, line) in hosts_file.lines().enumerate() {
-            if let Ok((_, (domain, host))) = host(line) {
-                hosts.insert(domain, (line_number, host));
-            }
-        }
+    pub fn request_unlock_scheduled(
+        &mut self,
+        socket: net::UnixStream,
+        config: &Config,
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let mut names: Vec<_> = config
+            .entries
+            .iter()
+            .filter(|(name, entry)| self.scheduled_unlock(name, entry, &now) == Some(true))
+            .filter(|(name, _)| self.is_locked.get(*name).or_if(|is_locked| *is_locked))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
 
-        Hosts {
-            hosts_file: hosts_file.lines().map(ToOwned::to_owned).collect(),
-            hosts: hosts,
-        }
-    }
+        let results = names
+            .into_iter()
+            .map(|name| {
+                let result = self.unlock(name, &config.entries[name], &config.after_unlock);
+                (name.clone(), result.err().map(|e| format!("{:?}", e)))
+            })
+            .collect();
 
-    fn is_locked(&self, domain: &str) -> bool {
-        match self.hosts.get(domain) {
-            None => false,
-            Some((_, host)) => match host {
-                Host::CommentedOut => false,
-                Host::Locked => true,
-            },
-        }
+        respond(socket, &UnlockScheduledResponse { results })
     }
 
-    fn host_line(&self, domain: &str, is_locked: bool) -> String {
-        if is_locked {
-            format!("127.0.0.1 {}", domain)
-        } else {
-            format!("# 127.0.0.1 {}", domain)
+    pub fn request_lock(
+        &mut self,
+        socket: net::UnixStream,
+        name: &str,
+        entry: &Entry,
+        after_lock: &Option<String>,
+    ) -> Result<()> {
+        if self.is_locked.get(name).and_if(|is_locked| *is_locked) {
+            return respond(socket, &LockResponse::AlreadyLocked);
         }
-    }
 
-    fn write_state(&mut self, domain: &str, is_locked: bool) {
-        match self.hosts.get(domain).as_deref() {
-            Some((line_number, _)) => {
-                self.hosts_file[*line_number] = self.host_line(domain, is_locked)
-            }
-            None => self.hosts_file.push(self.host_line(domain, is_locked)),
+        // Keep the schedule from unlocking it again before its window ends.
+        if let Some(locked_at) = self.scheduled_lock_time_of(name, entry) {
+            self.locked_early.set(name, locked_at);
         }
-    }
+        let response = match self.lock(name, entry, after_lock) {
+            Ok(()) => LockResponse::Locked,
+            Err(e) => LockResponse::Fail {
+                cause: format!("{:?}", e),
+            },
+        };
 
-    fn export(&self) -> String {
-        self.hosts_file.join("\n")
+        respond(socket, &response)
     }
 
-    fn save(&self) -> Result<()> {
-        fs::write("/etc/hosts", self.export())?;
-        Ok(())
+    pub fn request_why(&self, socket: net::UnixStream, name: &str, entry: &Entry) -> Result<()> {
+        let is_locked = self.is_locked.get(name).copied();
+        let response = WhyResponse {
+            is_locked,
+            reason: self.explain(name, entry, is_locked),
+        };
+
+        respond(socket, &response)
     }
-}
 
-fn read_hosts() -> Result<String> {
-    let content = fs::read_to_string("/etc/hosts")?;
-    Ok(content)
-}
+    /// Explains the state of an entry, following the precedence of `update`.
+    fn explain(&self, name: &str, entry: &Entry, is_locked: Option<bool>) -> String {
+        let now = self.clock.now();
+        let is_locked = match is_locked {
+            Some(is_locked) => is_locked,
+            None => return "senklot has not managed it yet".to_owned(),
+        };
+
+        if let Some(until) = self.focus_until(name) {
+            return format!("focus session until {}", until.format("%F %R"));
+        }
+        if let Some(until) = self.locked_early.get(name) {
+            return format!("locked manually until {}", until.format("%F %R"));
+        }
+        if let Some(until) = self.timed_unlocks.get(name).filter(|_| !is_locked) {
+            return format!("unlocked on request until {}", until.format("%F %R"));
+        }
+
+        let reason = match &entry.restriction {
+            Restriction::Static { .. } if entry.is_holiday(&now) => "today is a holiday".to_owned(),
+            Restriction::Static { unlock } => match unlock.iter().find(|d| d.contains(&now)) {
+                Some(window) => format!(
+                    "scheduled window {}-{} active",
+                    window.begin.format("%R"),
+                    window.end.format("%R")
+                ),
+                None => "outside every scheduled window".to_owned(),
+            },
+            Restriction::Dynamic { .. } if !is_locked => match self.lock_time_of(name, entry) {
+                Some(locked_at) => {
+                    format!("unlocked on request until {}", locked_at.format("%F %R"))
+                }
+                None => "unlocked on request".to_owned(),
+            },
+            Restriction::Dynamic { .. } => match self.unlock_time_of(name, entry) {
+                Some(unlocked_at) if now < unlocked_at => {
+                    format!("cooling down until {}", unlocked_at.format("%F %R"))
+                }
+                _ => "waiting to be unlocked on request".to_owned(),
+            },
+            Restriction::Manual(_) if !is_locked => {
+                "unlocked on request until locked manually".to_owned()
+            }
+            Restriction::Manual(_) => "locked on request".to_owned(),
+        };
+
+        match self.scheduled_unlock(name, entry, &now) {
+            Some(should_unlock) if should_unlock == is_locked => format!(
+                "{}; it will be {} on the next tick unless vetoed",
+                reason,
+                if should_unlock { "unlocked" } else { "locked" }
+            ),
+            _ => reason,
+        }
+    }
+
+    pub fn request_remaining(
+        &self,
+        socket: net::UnixStream,
+        name: &str,
+        entry: &Entry,
+    ) -> Result<()> {
+        let response = if self.is_locked.get(name).or_if(|is_locked| *is_locked) {
+            RemainingResponse::Locked
+        } else {
+            match self.lock_time_of(name, entry) {
+                Some(locked_at) => RemainingResponse::Unlocked { locked_at },
+                None => RemainingResponse::UnlockedIndefinitely,
+            }
+        };
+
+        respond(socket, &response)
+    }
+
+    /// When an unlocked entry locks again: at the end of a timed unlock or of its
+    /// schedule, whichever comes first.
+    fn lock_time_of(&self, name: &str, entry: &Entry) -> Option<LocalTime> {
+        let scheduled = self.scheduled_lock_time_of(name, entry);
+        match self.timed_unlocks.get(name) {
+            Some(until) => Some(scheduled.map_or(*until, |scheduled| scheduled.min(*until))),
+            None => scheduled,
+        }
+    }
+
+    fn scheduled_lock_time_of(&self, name: &str, entry: &Entry) -> Option<LocalTime> {
+        let now = self.clock.now();
+
+        match &entry.restriction {
+            Restriction::Static { .. } if entry.is_holiday(&now) => {
+                now.date().succ().and_hms_opt(0, 0, 0)
+            }
+            Restriction::Static { unlock } => {
+                let currently_unlocking = unlock.iter().find(|d| d.contains(&now))?;
+                let end = currently_unlocking.end;
+
+                if now.time() <= end {
+                    now.date().and_time(end)
+                } else {
+                    now.date().succ().and_time(end)
+                }
+            }
+            Restriction::Dynamic { period, .. } => self
+                .last_unlocked
+                .get(name)
+                .map(|last_unlocked| *last_unlocked + *period),
+            Restriction::Manual(_) => None,
+        }
+    }
+
+    fn unlock_time_of(&self, name: &str, entry: &Entry) -> Option<LocalTime> {
+        if self.is_locked.get(name).or_if(|is_locked| !is_locked) {
+            return None;
+        }
+
+        match entry.restriction {
+            Restriction::Static { .. } | Restriction::Manual(_) => None,
+            Restriction::Dynamic {
+                cool_time,
+                cooldown_from,
+                ..
+            } => self
+                .cooldown_start_of(name, cooldown_from)
+                .map(|cooldown_start| *cooldown_start + cool_time),
+        }
+    }
+
+    fn cooldown_start_of(&self, name: &str, cooldown_from: CooldownFrom) -> Option<&LocalTime> {
+        match cooldown_from {
+            CooldownFrom::UnlockStart => self.last_unlocked.get(name),
+            CooldownFrom::UnlockEnd => self.last_locked.get(name),
+        }
+    }
+
+    pub fn unlock(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        after_unlock: &Option<String>,
+    ) -> Result<()> {
+        let _scope = LogScope::new(name, "unlock");
+        if self.set_unlocked(name, entry)? {
+            self.record_transition(name, false);
+            if self.commit()? != Commit::Deferred {
+                self.save()?;
+                if self.take_transition(name) {
+                    self.after_unlocked(name, entry, after_unlock)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_unlocked(&mut self, name: &str, entry: &Entry) -> Result<bool> {
+        if self.is_locked.get(name).and_if(|is_locked| !is_locked) {
+            return Ok(false);
+        }
+        if let Some(until) = self.focus_until(name) {
+            return Err(anyhow!("Locked by a focus session until {}", until));
+        }
+        self.check_cool_down(name, entry)?;
+
+        self.locked_early.remove(name);
+        self.is_locked.set(name, false);
+        self.unlocked_since.set(name, self.clock.now());
+
+        if matches!(entry.restriction, Restriction::Dynamic{..}) {
+            self.last_unlocked.set(name, self.clock.now());
+        }
+
+        Ok(true)
+    }
+
+    fn check_cool_down(&self, name: &str, entry: &Entry) -> Result<()> {
+        if let Restriction::Dynamic {
+            cool_time,
+            cooldown_from,
+            ..
+        } = entry.restriction
+        {
+            if self
+                .cooldown_start_of(name, cooldown_from)
+                .and_if(|cooldown_start| self.clock.now() < *cooldown_start + cool_time)
+            {
+                return Err(anyhow!("Not have been cool down yet"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn lock(&mut self, name: &str, entry: &Entry, after_lock: &Option<String>) -> Result<()> {
+        let _scope = LogScope::new(name, "lock");
+        if self.set_locked(name, entry) {
+            self.record_transition(name, true);
+            if self.commit()? != Commit::Deferred {
+                self.save()?;
+                if self.take_transition(name) {
+                    self.after_locked(name, entry, after_lock)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_locked(&mut self, name: &str, entry: &Entry) -> bool {
+        if self.is_locked.get(name).and_if(|is_locked| *is_locked) {
+            return false;
+        }
+
+        self.is_locked.set(name, true);
+        self.unlocked_since.remove(name);
+        self.timed_unlocks.remove(name);
+        self.last_nagged.remove(name);
+
+        if matches!(entry.restriction, Restriction::Dynamic{..}) {
+            self.last_locked.set(name, self.clock.now());
+        }
+
+        true
+    }
+
+    fn is_vetoed(&mut self, name: &str, lock: bool, config: &Config) -> bool {
+        if self.is_locked.get(name).cloned() == Some(lock) {
+            return false;
+        }
+
+        let (hook, action) = if lock {
+            (&config.before_lock, "lock")
+        } else {
+            (&config.before_unlock, "unlock")
+        };
+        let hook = match hook {
+            Some(hook) if !self.dry_run => hook,
+            _ => return false,
+        };
+        let _scope = LogScope::new(name, action);
+
+        let vetoes = self.vetoes.get(name).cloned().unwrap_or(0);
+        if config.max_vetoes.and_if(|max_vetoes| vetoes >= max_vetoes) {
+            warn!(
+                "Scheduled {} of {} was vetoed {} times, applying it anyway",
+                action, name, vetoes
+            );
+            self.vetoes.remove(name);
+            return false;
+        }
+
+        let cwd = config
+            .entries
+            .get(name)
+            .and_then(|entry| entry.hook_cwd.as_deref());
+        match run_before_hook(hook, name, cwd, BEFORE_HOOK_TIMEOUT) {
+            Ok(true) => {
+                info!("Scheduled {} of {} was vetoed", action, name);
+                self.vetoes.set(name, vetoes + 1);
+                true
+            }
+            Ok(false) => {
+                self.vetoes.remove(name);
+                false
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                false
+            }
+        }
+    }
+
+    fn excute_command(
+        &mut self,
+        command: &str,
+        content_name: &str,
+        cwd: Option<&Path>,
+    ) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.hooks.spawn(hook_command(command, content_name, cwd))?;
+        Ok(())
+    }
+
+    /// Keeps a transition until its write lands. A transition undoing one still
+    /// pending cancels it, since the hosts file never showed either.
+    fn record_transition(&mut self, name: &str, is_locked: bool) {
+        match self
+            .pending_transitions
+            .iter()
+            .position(|(pending, _)| pending == name)
+        {
+            Some(i) => {
+                self.pending_transitions.remove(i);
+            }
+            None => self.pending_transitions.push((name.to_owned(), is_locked)),
+        }
+    }
+
+    /// Takes the pending transition of `name`, returning whether there was one.
+    fn take_transition(&mut self, name: &str) -> bool {
+        let len = self.pending_transitions.len();
+        self.pending_transitions
+            .retain(|(pending, _)| pending != name);
+        self.pending_transitions.len() < len
+    }
+
+    /// Saves the state and runs the hooks of every transition whose write has
+    /// landed, including those left by a deferred or failed write.
+    fn run_pending_hooks(&mut self, config: &Config) -> Vec<anyhow::Error> {
+        let pending = mem::take(&mut self.pending_transitions);
+        if pending.is_empty() {
+            return Vec::new();
+        }
+
+        let mut errors = Vec::new();
+        // Whatever the backend, a transition must survive a restart.
+        if let Err(e) = self.save() {
+            errors.push(e);
+        }
+        for (name, is_locked) in pending {
+            let entry = match config.entries.get(&name) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let result = if is_locked {
+                self.after_locked(&name, entry, &config.after_lock)
+            } else {
+                self.after_unlocked(&name, entry, &config.after_unlock)
+            };
+            if let Err(e) = result {
+                errors.push(e);
+            }
+        }
+        errors
+    }
+
+    fn after_unlocked(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        after_unlock: &Option<String>,
+    ) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        if let Some(signal) = &entry.signal_on_unlock {
+            send_signal(signal)?;
+        }
+
+        if let Some(cmd) = after_unlock {
+            self.excute_command(cmd, name, entry.hook_cwd.as_deref())?;
+        }
+
+        Ok(())
+    }
+
+    fn after_locked(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        after_lock: &Option<String>,
+    ) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        if let Some(signal) = &entry.signal_on_lock {
+            send_signal(signal)?;
+        }
+
+        if let Some(cmd) = after_lock {
+            self.excute_command(cmd, name, entry.hook_cwd.as_deref())?;
+        }
+
+        Ok(())
+    }
+
+    fn nag(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        on_nag: &Option<String>,
+        now: &LocalTime,
+    ) -> Result<()> {
+        let nag_after = match entry.nag_after {
+            Some(nag_after) => nag_after,
+            None => return Ok(()),
+        };
+        let unlocked_since = match self.unlocked_since.get(name) {
+            Some(unlocked_since) => *unlocked_since,
+            None => return Ok(()),
+        };
+        let nag_from = self
+            .last_nagged
+            .get(name)
+            .cloned()
+            .unwrap_or(unlocked_since);
+
+        if *now < nag_from + nag_after {
+            return Ok(());
+        }
+        self.last_nagged.set(name, *now);
+
+        info!(
+            "{} has been unlocked for {}",
+            name,
+            format_duration(now.signed_duration_since(unlocked_since))
+        );
+
+        if let Some(cmd) = on_nag {
+            self.excute_command(cmd, name, entry.hook_cwd.as_deref())?;
+        }
+
+        Ok(())
+    }
+
+    // A domain shared by several entries stays locked while any of them is locked.
+    fn domanin_is_locked(&self, domain: &str) -> bool {
+        self.domain_map.get(domain).and_if(|entries| {
+            entries
+                .iter()
+                .any(|entry| self.is_locked.get(entry).and_if(|is_locked| *is_locked))
+        })
+    }
+
+    fn redirect_of(&self, domain: &str) -> IpAddr {
+        self.redirect_map
+            .get(domain)
+            .cloned()
+            .unwrap_or(self.default_redirect)
+    }
+
+    fn backend_of(&self, domain: &str) -> Backend {
+        self.domain_backends
+            .get(domain)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether `backend` should block `domain` now; `release` unblocks everything.
+    fn locks_with(&self, backend: Backend, domain: &str, release: bool) -> bool {
+        !release && self.backend_of(domain) == backend && self.domanin_is_locked(domain)
+    }
+
+    fn owner_of(&self, domain: &str) -> &str {
+        self.domain_map
+            .get(domain)
+            .and_then(|entries| entries.iter().min())
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn redirects_of(&self, domain: &str) -> Vec<IpAddr> {
+        let redirect_ip = self.redirect_of(domain);
+        match self.redirect_v6 {
+            Some(redirect_v6) if redirect_ip.is_ipv4() => vec![redirect_ip, redirect_v6.into()],
+            _ => vec![redirect_ip],
+        }
+    }
+
+    /// Stops writing anything or running hooks; the hosts file that would have
+    /// been written is kept for `previewed_hosts` instead.
+    pub fn set_dry_run(&mut self) {
+        self.dry_run = true;
+    }
+
+    /// The hosts file before and after the last write skipped by a dry run.
+    pub fn previewed_hosts(&self) -> Option<&(String, String)> {
+        self.previewed_hosts.as_ref()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        write_private(&self.paths.state_file, &self.export())
+    }
+
+    /// Reaps finished hooks and starts queued ones in their place.
+    pub fn poll_hooks(&mut self) -> Result<()> {
+        Ok(self.hooks.poll()?)
+    }
+
+    pub fn commit(&mut self) -> Result<Commit> {
+        // A failing backend must not keep the others from enforcing their entries.
+        let mut errors = self.commit_blockers(false);
+        let commit = self.commit_hosts_file().unwrap_or_else(|e| {
+            errors.push(e);
+            Commit::Deferred
+        });
+        join_errors(errors).map(|()| commit)
+    }
+
+    fn commit_hosts_file(&mut self) -> Result<Commit> {
+        let now = self.clock.now();
+        if self.hosts_backoff.and_if(|(until, _)| now < until) {
+            self.pending_write = true;
+            return Ok(Commit::Deferred);
+        }
+
+        match self.write_hosts_file(false) {
+            Err(e) if e.is::<HostsNotWritable>() => {
+                let delay = self
+                    .hosts_backoff
+                    .map(|(_, delay)| (delay * 2).min(max_hosts_backoff()))
+                    .unwrap_or_else(min_hosts_backoff);
+                self.hosts_backoff = Some((now + delay, delay));
+                self.pending_write = true;
+
+                Err(e.context(format!("Retrying in {}", format_duration(delay))))
+            }
+            result => {
+                self.hosts_backoff = None;
+                self.pending_write = false;
+                result.map(|written| {
+                    if written {
+                        Commit::Written
+                    } else {
+                        Commit::Unchanged
+                    }
+                })
+            }
+        }
+    }
+
+    pub fn release(&mut self) -> Result<()> {
+        let mut errors = self.commit_blockers(true);
+        if let Err(e) = self.write_hosts_file(true) {
+            errors.push(e);
+        }
+        join_errors(errors)
+    }
+
+    /// Receives whenever the blocker has something new to commit.
+    pub fn blocker_refreshed(&self) -> channel::Receiver<()> {
+        self.blocker_refreshed.1.clone()
+    }
+
+    /// Whether the hosts file still holds exactly what senklot last wrote there,
+    /// in which case a watcher event for it is an echo of senklot's own write.
+    pub fn hosts_are_own_write(&self) -> bool {
+        self.written_hosts
+            .as_ref()
+            .and_if(|written| read_hosts(&self.paths).ok().as_ref() == Some(written))
+    }
+
+    /// Commits every blocker, returning the errors of those that failed.
+    fn commit_blockers(&mut self, release: bool) -> Vec<anyhow::Error> {
+        if self.domain_map.is_empty() {
+            return Vec::new();
+        }
+
+        let mut blockers = mem::take(&mut self.blockers);
+        let errors = blockers
+            .iter_mut()
+            .filter_map(|(backend, blocker)| {
+                self.apply_to_blocker(*backend, blocker.as_mut(), release)
+                    .with_context(|| format!("The {:?} backend failed", backend))
+                    .err()
+            })
+            .collect();
+        self.blockers = blockers;
+        errors
+    }
+
+    fn apply_to_blocker(
+        &self,
+        backend: Backend,
+        blocker: &mut dyn Blocker,
+        release: bool,
+    ) -> Result<()> {
+        for domain in self.domain_map.keys() {
+            if self.backend_of(domain) == backend {
+                blocker.set_locked(domain, self.locks_with(backend, domain, release));
+            }
+        }
+
+        if self.dry_run {
+            return Ok(());
+        }
+        blocker.commit()
+    }
+
+    /// Lines of domains using another backend are released, including those
+    /// written while the hosts file was their backend.
+    /// Returns whether the hosts file was written.
+    fn write_hosts_file(&mut self, release: bool) -> Result<bool> {
+        if self.domain_map.is_empty() {
+            return Ok(false);
+        }
+
+        for _ in 0..MAX_HOSTS_MERGES {
+            match self.try_write_hosts_file(release) {
+                Err(e) if e.is::<HostsChanged>() => {
+                    info!(
+                        "{} was changed by another program, merging onto its new content",
+                        self.paths.hosts_file.display()
+                    );
+                }
+                result => return result,
+            }
+        }
+
+        Err(anyhow!(
+            "{} kept changing while being written, trying again at the next tick",
+            self.paths.hosts_file.display()
+        ))
+    }
+
+    fn try_write_hosts_file(&mut self, release: bool) -> Result<bool> {
+        let content = read_hosts(&self.paths)?;
+        let (mut blocker, changes) = self.apply_to_hosts(&content, release);
+
+        if changes.is_empty() {
+            return Ok(false);
+        }
+
+        let managed_lines = blocker.hosts.managed_lines();
+        if managed_lines > self.max_hosts_lines {
+            return Err(anyhow!(
+                "Refusing to write {} managed lines to {} (`max_hosts_lines` is {})",
+                managed_lines,
+                self.paths.hosts_file.display(),
+                self.max_hosts_lines
+            ));
+        }
+
+        if self.dry_run {
+            self.previewed_hosts = Some((content, blocker.hosts.export()));
+            return Ok(false);
+        }
+
+        blocker.commit()?;
+        let written = blocker.hosts.export();
+
+        info!("{}", summarize_changes(&self.paths.hosts_file, &changes));
+        self.report_reverted_edits(&changes);
+        self.written_hosts = Some(written);
+        self.flush_dns();
+
+        Ok(true)
+    }
+
+    /// Logs the changes that undo someone else's edit: the domain already had the
+    /// wanted state the last time senklot wrote the hosts file.
+    fn report_reverted_edits(&self, changes: &[(&str, bool)]) {
+        let written = match &self.written_hosts {
+            Some(written) => Hosts::parse(written),
+            None => return,
+        };
+
+        for (domain, is_locked) in changes {
+            if written.is_locked(domain, false) == *is_locked {
+                info!(
+                    "{} was {} outside senklot; {} it again",
+                    domain,
+                    if *is_locked { "unlocked" } else { "locked" },
+                    if *is_locked { "locking" } else { "unlocking" }
+                );
+            }
+        }
+    }
+
+    /// Runs in the hook pool like the other commands, so a slow resolver
+    /// cannot hold up the daemon.
+    fn flush_dns(&mut self) {
+        let command = match &self.flush_dns_command {
+            Some(command) => command,
+            None => return,
+        };
+
+        let mut flush = process::Command::new("sh");
+        flush.arg("-c").arg(command);
+        match self.hooks.spawn(flush) {
+            Ok(()) => info!("Flushing the DNS cache"),
+            Err(e) => warn!("Unable to flush the DNS cache: {}", e),
+        }
+    }
+
+    /// Removes senklot's lines for domains no longer in the config.
+    pub fn clean_stale_hosts(&mut self) -> Result<()> {
+        let mut hosts = Hosts::parse(&read_hosts(&self.paths)?);
+        let mut removed = Vec::new();
+        hosts.hosts_file.retain(|line| match managed_host(line) {
+            Some((domain, _, _)) if !self.domain_map.contains_key(&domain) => {
+                removed.push(domain);
+                false
+            }
+            _ => true,
+        });
+
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        hosts.save(&self.paths, None)?;
+        self.written_hosts = Some(hosts.export());
+        for domain in removed {
+            info!(
+                "Removed the stale {} line for {}",
+                self.paths.hosts_file.display(),
+                domain
+            );
+        }
+
+        Ok(())
+    }
+
+    fn apply_to_hosts(
+        &self,
+        content: &str,
+        release: bool,
+    ) -> (HostsBlocker<'_>, Vec<(&str, bool)>) {
+        let mut hosts = Hosts::parse(content);
+        hosts.adopt_legacy_lines(|domain| {
+            Some(self.owner_of(domain)).filter(|_| self.domain_map.contains_key(domain))
+        });
+        let mut blocker = HostsBlocker {
+            hosts,
+            state: self,
+            expected: Some(content.to_owned()).filter(|_| self.verify_hosts_before_write),
+        };
+        let mut changes = Vec::new();
+
+        for domain in self.domain_map.keys() {
+            let lock_state = self.locks_with(Backend::Hosts, domain, release);
+            if !blocker.is_up_to_date(domain, lock_state) {
+                blocker.set_locked(domain, lock_state);
+                changes.push((domain.as_str(), lock_state));
+            }
+        }
+
+        (blocker, changes)
+    }
+
+    fn scheduled_unlock(&self, name: &str, entry: &Entry, now: &LocalTime) -> Option<bool> {
+        match &entry.restriction {
+            Restriction::Static { .. } => entry.in_unlock_window(now),
+            Restriction::Dynamic { period, .. } => Some(
+                self.last_unlocked
+                    .get(name)
+                    .and_if(|last_unlocked| *now < *last_unlocked + *period),
+            ),
+            Restriction::Manual(_) => None,
+        }
+    }
+
+    /// Holds a scheduled lock back for the entry's `lock_grace`, so connections
+    /// in flight when the window closes can finish.
+    fn defer_lock(
+        &mut self,
+        name: &str,
+        entry: &Entry,
+        should_unlock: Option<bool>,
+        now: &LocalTime,
+    ) -> Option<bool> {
+        let is_unlocked = self.is_locked.get(name) == Some(&false);
+        let grace = match entry.lock_grace {
+            Some(grace) if should_unlock == Some(false) && is_unlocked => grace,
+            _ => {
+                self.deferred_locks.remove(name);
+                return should_unlock;
+            }
+        };
+
+        let until = *self
+            .deferred_locks
+            .entry(name.to_owned())
+            .or_insert_with(|| *now + grace);
+        if *now < until {
+            None
+        } else {
+            self.deferred_locks.remove(name);
+            Some(false)
+        }
+    }
+
+    pub fn update(&mut self, config: &Config) -> Result<(), Vec<anyhow::Error>> {
+        if self
+            .fast_tick_until
+            .and_if(|until| self.clock.now() >= until)
+        {
+            self.fast_tick_until = None;
+            info!("Fast tick ended");
+        }
+
+        if self
+            .focus
+            .as_ref()
+            .and_if(|focus| self.clock.now() >= focus.until)
+        {
+            self.focus = None;
+            info!("Focus session ended");
+        }
+
+        if config.entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut errors = Vec::new();
+
+        if let Err(e) = self.hooks.poll() {
+            errors.push(anyhow::Error::new(e));
+        }
+
+        let now: LocalTime = self.clock.now();
+        self.locked_early.retain(|_, until| now < *until);
+        for (name, entry) in &config.entries {
+            let relock = self.timed_unlocks.get(name).and_if(|until| now >= *until);
+            if relock {
+                self.timed_unlocks.remove(name);
+                if let Some(locked_at) = self.scheduled_lock_time_of(name, entry) {
+                    self.locked_early.set(name, locked_at);
+                }
+            }
+
+            let should_unlock = self.scheduled_unlock(name, entry, &now);
+            let should_unlock = if self.is_locked.contains_key(name) {
+                should_unlock
+            } else {
+                match config.initial_state {
+                    InitialState::Locked => Some(false),
+                    InitialState::Unlocked => Some(true),
+                    InitialState::Schedule => should_unlock,
+                }
+            };
+            // A timed unlock holds until it expires, whatever the schedule says.
+            let should_unlock = if self.timed_unlocks.get(name).and_if(|until| now < *until) {
+                None
+            } else {
+                should_unlock
+            };
+
+            // Only a scheduled transition that is about to happen is put to the
+            // before-hooks; forced locks cannot be vetoed.
+            let should_unlock = if relock
+                || self.focus_until(name).is_some()
+                || self.locked_early.contains_key(name)
+            {
+                Some(false)
+            } else {
+                self.defer_lock(name, entry, should_unlock, &now)
+                    .filter(|should_unlock| !self.is_vetoed(name, !should_unlock, config))
+            };
+
+            match should_unlock {
+                Some(true) => match self.set_unlocked(name, entry) {
+                    Ok(true) => self.record_transition(name, false),
+                    Ok(false) => {}
+                    Err(e) => errors.push(e),
+                },
+                Some(false) if self.set_locked(name, entry) => self.record_transition(name, true),
+                Some(false) | None => {}
+            }
+
+            if let Err(e) = self.nag(name, entry, &config.on_nag, &now) {
+                errors.push(e);
+            }
+        }
+
+        // Write /etc/hosts once for the whole tick, then run the hooks of every
+        // entry that changed. A deferred write keeps its transitions pending
+        // until a later tick lands it.
+        let retry = self.pending_write
+            && self
+                .hosts_backoff
+                .is_none_or(|(until, _)| self.clock.now() >= until);
+        if !self.pending_transitions.is_empty() || retry {
+            match self.commit() {
+                Ok(Commit::Written) | Ok(Commit::Unchanged) => {
+                    errors.extend(self.run_pending_hooks(config));
+                }
+                Ok(Commit::Deferred) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+named!(addr_domain(&str) -> String,
+    map!(recognize!(many1!(none_of("\t #\r"))), |s| s.to_owned())
+);
+named!(inline_comment(&str) -> String,
+    map!(tuple!(space0, tag!("#"), rest), |(_, _, comment)| comment.trim().to_owned())
+);
+named!(comment_out(&str) -> (String, Host, Option<String>),
+    map!(tuple!(space0, tag!("#"), locked_host), |(_, _, (domain, host, comment))| (domain, host.comment_out(), comment))
+);
+named!(locked_host(&str) -> (String, Host, Option<String>),
+    map!(tuple!(space0, addr_domain, space1, addr_domain, opt!(complete!(inline_comment))),
+        |(_, addr, _, domain, comment)| (domain, Host::Locked(addr), comment))
+);
+named!(host(&str) -> (String, Host, Option<String>),
+    alt!( locked_host
+        | comment_out
+        )
+);
+
+enum Host {
+    Locked(String),
+    CommentedOut(String),
+}
+
+impl Host {
+    fn comment_out(self) -> Host {
+        match self {
+            Host::Locked(addr) | Host::CommentedOut(addr) => Host::CommentedOut(addr),
+        }
+    }
+
+    fn is_ipv6(&self) -> bool {
+        match self {
+            Host::Locked(addr) | Host::CommentedOut(addr) => addr.contains(':'),
+        }
+    }
+}
+
+/// Lines written by senklot end with `# senklot:<entry>`; others are never
+/// touched, except the unmarked lines written before the marker existed.
+const OWNER_MARKER: &str = "senklot:";
+
+fn managed_host(line: &str) -> Option<(String, Host, String)> {
+    let (_, (domain, host, comment)) = host(line).ok()?;
+    let owner = split_comment(&comment?).1?.to_owned();
+    Some((domain.to_lowercase(), host, owner))
+}
+
+/// The user's note on a managed line, kept when senklot rewrites the line.
+fn line_note(line: &str) -> Option<String> {
+    let (_, (_, _, comment)) = host(line).ok()?;
+    split_comment(&comment?).0.map(str::to_owned)
+}
+
+/// Splits a trailing comment into the user's note and the owner marked by
+/// senklot, which comes last: `127.0.0.1 example.com # note # senklot:sns`.
+fn split_comment(comment: &str) -> (Option<&str>, Option<&str>) {
+    let (note, last) = match comment.rfind('#') {
+        Some(i) => (comment[..i].trim(), comment[i + 1..].trim()),
+        None => ("", comment),
+    };
+    match last.strip_prefix(OWNER_MARKER) {
+        Some(owner) => (Some(note).filter(|note| !note.is_empty()), Some(owner)),
+        None => (Some(comment).filter(|comment| !comment.is_empty()), None),
+    }
+}
+
+/// A line written by the first releases, which did not mark their lines: just
+/// `<ip> <domain>`, or `# <ip> <domain>` when unlocked.
+fn legacy_host(line: &str) -> Option<(String, Host)> {
+    let (_, (domain, host, comment)) = host(line).ok()?;
+    let (addr, canonical) = match &host {
+        Host::Locked(addr) => (addr, format!("{} {}", addr, domain)),
+        Host::CommentedOut(addr) => (addr, format!("# {} {}", addr, domain)),
+    };
+    if comment.is_some() || line != canonical || addr.parse::<IpAddr>().is_err() {
+        return None;
+    }
+
+    Some((domain.to_lowercase(), host))
+}
+
+type HostMap = HashMap<String, (usize, Host, String)>;
+
+struct Hosts {
+    hosts_file: Vec<String>,
+    hosts: HostMap,
+    hosts_v6: HostMap,
+    line_ending: &'static str,
+    trailing_newline: bool,
+}
+
+impl Hosts {
+    fn parse(hosts_file: &str) -> Hosts {
+        let mut hosts = HashMap::new();
+        let mut hosts_v6 = HashMap::new();
+        let mut lines = Vec::new();
+        for (line_number, line) in hosts_file.lines().enumerate() {
+            if let Some((domain, host, owner)) = managed_host(line) {
+                let family = if host.is_ipv6() {
+                    &mut hosts_v6
+                } else {
+                    &mut hosts
+                };
+                family.insert(domain, (line_number, host, owner));
+            }
+            lines.push(line.to_owned());
+        }
+
+        let crlf = hosts_file.matches("\r\n").count();
+        let lf = hosts_file.matches('\n').count() - crlf;
+
+        Hosts {
+            hosts_file: lines,
+            hosts,
+            hosts_v6,
+            line_ending: if crlf > lf { "\r\n" } else { "\n" },
+            // An empty file gets one too once senklot adds its lines.
+            trailing_newline: hosts_file.is_empty() || hosts_file.ends_with('\n'),
+        }
+    }
+
+    fn family(&self, ipv6: bool) -> &HostMap {
+        if ipv6 {
+            &self.hosts_v6
+        } else {
+            &self.hosts
+        }
+    }
+
+    fn family_mut(&mut self, ipv6: bool) -> &mut HostMap {
+        if ipv6 {
+            &mut self.hosts_v6
+        } else {
+            &mut self.hosts
+        }
+    }
+
+    fn is_locked(&self, domain: &str, ipv6: bool) -> bool {
+        match self.family(ipv6).get(domain) {
+            None => false,
+            Some((_, host, _)) => match host {
+                Host::CommentedOut(_) => false,
+                Host::Locked(_) => true,
+            },
+        }
+    }
+
+    fn is_locked_to(&self, domain: &str, redirect_ip: &IpAddr) -> bool {
+        match self.family(redirect_ip.is_ipv6()).get(domain) {
+            Some((_, Host::Locked(addr), _)) => {
+                addr.parse::<IpAddr>().ok().as_ref() == Some(redirect_ip)
+            }
+            _ => false,
+        }
+    }
+
+    fn host_line(
+        &self,
+        domain: &str,
+        is_locked: bool,
+        redirect_ip: impl fmt::Display,
+        owner: &str,
+        note: Option<&str>,
+    ) -> String {
+        let mut line = if is_locked {
+            format!("{} {}", redirect_ip, domain)
+        } else {
+            format!("# {} {}", redirect_ip, domain)
+        };
+
+        if let Some(note) = note {
+            line += &format!(" # {}", note);
+        }
+        format!("{} # {}{}", line, OWNER_MARKER, owner)
+    }
+
+    /// Takes over the lines senklot wrote before it marked them, for the domains
+    /// `owner_of` knows.
+    fn adopt_legacy_lines<'a>(&mut self, owner_of: impl Fn(&str) -> Option<&'a str>) {
+        for (line_number, line) in self.hosts_file.iter().enumerate() {
+            let (domain, host) = match legacy_host(line) {
+                Some(legacy) => legacy,
+                None => continue,
+            };
+            let owner = match owner_of(&domain) {
+                Some(owner) => owner.to_owned(),
+                None => continue,
+            };
+
+            let family = if host.is_ipv6() {
+                &mut self.hosts_v6
+            } else {
+                &mut self.hosts
+            };
+            family.entry(domain).or_insert((line_number, host, owner));
+        }
+    }
+
+    /// Comments out the line locking `domain` in one address family, if any.
+    fn unlock(&mut self, domain: &str, ipv6: bool, owner: &str) {
+        let addr = match self.family(ipv6).get(domain) {
+            Some((_, Host::Locked(addr), _)) => addr.parse::<IpAddr>(),
+            _ => return,
+        };
+        if let Ok(addr) = addr {
+            self.write_state(domain, false, &addr, owner);
+        }
+    }
+
+    fn write_state(&mut self, domain: &str, is_locked: bool, redirect_ip: &IpAddr, owner: &str) {
+        let existing = self
+            .family(redirect_ip.is_ipv6())
+            .get(domain)
+            .map(|(line_number, _, _)| *line_number);
+        let note = existing.and_then(|line_number| line_note(&self.hosts_file[line_number]));
+        let line = self.host_line(domain, is_locked, redirect_ip, owner, note.as_deref());
+
+        let line_number = match existing {
+            Some(line_number) => {
+                self.hosts_file[line_number] = line;
+                line_number
+            }
+            None => {
+                self.hosts_file.push(line);
+                self.hosts_file.len() - 1
+            }
+        };
+
+        // Keep the map in step so a later write edits this line instead of appending another.
+        let addr = redirect_ip.to_string();
+        let host = if is_locked {
+            Host::Locked(addr)
+        } else {
+            Host::CommentedOut(addr)
+        };
+        self.family_mut(redirect_ip.is_ipv6())
+            .insert(domain.to_owned(), (line_number, host, owner.to_owned()));
+    }
+
+    /// Rewrites managed lines in the canonical layout, returning their indices.
+    fn normalize(&mut self) -> Vec<usize> {
+        let mut changed = Vec::new();
+
+        for line_number in 0..self.hosts_file.len() {
+            let line = &self.hosts_file[line_number];
+            let note = line_note(line);
+            let canonical = match managed_host(line) {
+                Some((domain, Host::Locked(addr), owner)) => {
+                    self.host_line(&domain, true, addr, &owner, note.as_deref())
+                }
+                Some((domain, Host::CommentedOut(addr), owner)) => {
+                    self.host_line(&domain, false, addr, &owner, note.as_deref())
+                }
+                None => continue,
+            };
+
+            if self.hosts_file[line_number] != canonical {
+                self.hosts_file[line_number] = canonical;
+                changed.push(line_number);
+            }
+        }
+
+        changed
+    }
+
+    fn managed_lines(&self) -> usize {
+        self.hosts_file
+            .iter()
+            .filter(|line| managed_host(line).is_some())
+            .count()
+    }
+
+    fn export(&self) -> String {
+        let mut content = self.hosts_file.join(self.line_ending);
+        if self.trailing_newline && !content.is_empty() {
+            content.push_str(self.line_ending);
+        }
+        content
+    }
+
+    fn save(&self, paths: &Paths, expected: Option<&str>) -> Result<()> {
+        let replaced = replace_file(
+            &paths.hosts_file,
+            &paths.hosts_tmp(),
+            self.export().as_bytes(),
+            expected.map(str::as_bytes),
+        )
+        .map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                anyhow::Error::new(HostsNotWritable(paths.hosts_file.clone()))
+            } else {
+                anyhow::Error::new(e)
+            }
+        })?;
+
+        if replaced {
+            Ok(())
+        } else {
+            Err(anyhow::Error::new(HostsChanged))
+        }
+    }
+}
+
+/// The default blocker, which redirects locked domains in the hosts file.
+struct HostsBlocker<'a> {
+    hosts: Hosts,
+    state: &'a State,
+    /// What the hosts file held when `hosts` was parsed; if it holds something
+    /// else right before the write, `commit` fails with `HostsChanged`.
+    expected: Option<String>,
+}
+
+impl HostsBlocker<'_> {
+    fn is_up_to_date(&self, domain: &str, locked: bool) -> bool {
+        if !locked {
+            return !self.is_locked(domain);
+        }
+        self.state
+            .redirects_of(domain)
+            .iter()
+            .all(|redirect_ip| self.hosts.is_locked_to(domain, redirect_ip))
+            && self
+                .stray_families(domain)
+                .into_iter()
+                .all(|ipv6| !self.hosts.is_locked(domain, ipv6))
+    }
+
+    /// The address families `domain` is not redirected in, such as IPv6 after
+    /// `block_ipv6` was turned off, whose lines must no longer lock it.
+    fn stray_families(&self, domain: &str) -> Vec<bool> {
+        let redirects = self.state.redirects_of(domain);
+        [false, true]
+            .iter()
+            .copied()
+            .filter(|ipv6| !redirects.iter().any(|ip| ip.is_ipv6() == *ipv6))
+            .collect()
+    }
+}
+
+impl Blocker for HostsBlocker<'_> {
+    fn set_locked(&mut self, domain: &str, locked: bool) {
+        let owner = self.state.owner_of(domain);
+        for redirect_ip in self.state.redirects_of(domain) {
+            let is_up_to_date = if locked {
+                self.hosts.is_locked_to(domain, &redirect_ip)
+            } else {
+                !self.hosts.is_locked(domain, redirect_ip.is_ipv6())
+            };
+
+            if !is_up_to_date {
+                self.hosts.write_state(domain, locked, &redirect_ip, owner);
+            }
+        }
+
+        for ipv6 in self.stray_families(domain) {
+            self.hosts.unlock(domain, ipv6, owner);
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.hosts.save(&self.state.paths, self.expected.as_deref())
+    }
+
+    fn is_locked(&self, domain: &str) -> bool {
+        self.hosts.is_locked(domain, false) || self.hosts.is_locked(domain, true)
+    }
+}
+
+/// What `State::commit` did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Commit {
+    Written,
+    /// The hosts file already matched, or there is nothing to manage.
+    Unchanged,
+    /// Put off while backing off from an unwritable hosts file.
+    Deferred,
+}
+
+#[derive(Debug)]
+struct HostsNotWritable(PathBuf);
+
+impl fmt::Display for HostsNotWritable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is not writable; is it immutable (chattr +i) or are we non-root?",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for HostsNotWritable {}
+
+/// How many times a write is merged onto edits made while it was prepared.
+const MAX_HOSTS_MERGES: usize = 3;
+
+#[derive(Debug)]
+struct HostsChanged;
+
+impl fmt::Display for HostsChanged {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the hosts file changed while a write was prepared")
+    }
+}
+
+impl std::error::Error for HostsChanged {}
+
+/// One error for failures that happened independently, none hiding the others.
+fn join_errors(mut errors: Vec<anyhow::Error>) -> Result<()> {
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => {
+            let errors: Vec<_> = errors.iter().map(|e| format!("{:#}", e)).collect();
+            Err(anyhow!("{}", errors.join("\n")))
+        }
+    }
+}
+
+const MAX_REPORTED_DOMAINS: usize = 10;
+
+fn summarize_changes(hosts_file: &Path, changes: &[(&str, bool)]) -> String {
+    let describe = |is_locked: bool| {
+        let domains: Vec<_> = changes
+            .iter()
+            .filter(|(_, locked)| *locked == is_locked)
+            .map(|(domain, _)| *domain)
+            .collect();
+
+        let mut names = domains
+            .iter()
+            .take(MAX_REPORTED_DOMAINS)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ");
+        if domains.len() > MAX_REPORTED_DOMAINS {
+            names += &format!(", and {} more", domains.len() - MAX_REPORTED_DOMAINS);
+        }
+
+        (domains.len(), names)
+    };
+
+    let (locked, locked_names) = describe(true);
+    let (unlocked, unlocked_names) = describe(false);
+
+    format!(
+        "Updated {}: locked {} [{}], unlocked {} [{}]",
+        hosts_file.display(),
+        locked,
+        locked_names,
+        unlocked,
+        unlocked_names
+    )
+}
+
+pub struct ManagedDomain {
+    pub domain: String,
+    pub line_number: Option<usize>,
+    pub is_locked: bool,
+}
+
+/// Where each configured domain sits in the live /etc/hosts, 1-based.
+pub fn managed_domains(config: &Config) -> Result<Vec<ManagedDomain>> {
+    let hosts = Hosts::parse(&read_hosts(&config.paths)?);
+
+    let mut domains: Vec<_> = config
+        .entries
+        .values()
+        .flat_map(|entry| &entry.domains)
+        .collect();
+    domains.sort();
+    domains.dedup();
+
+    Ok(domains
+        .into_iter()
+        .map(|domain| ManagedDomain {
+            domain: domain.clone(),
+            line_number: hosts
+                .hosts
+                .get(domain)
+                .or_else(|| hosts.hosts_v6.get(domain))
+                .map(|(line_number, _, _)| line_number + 1),
+            is_locked: hosts.is_locked(domain, false)
+                && (!config.block_ipv6 || hosts.is_locked(domain, true)),
+        })
+        .collect())
+}
+
+/// Tidies senklot's lines in /etc/hosts after backing it up, returning the
+/// 1-based numbers of the lines that changed.
+pub fn format_hosts(paths: &Paths) -> Result<Vec<usize>> {
+    let mut hosts = Hosts::parse(&read_hosts(paths)?);
+    let changed = hosts.normalize();
+
+    if !changed.is_empty() {
+        fs::copy(&paths.hosts_file, paths.hosts_backup())
+            .with_context(|| format!("Unable to back up {}", paths.hosts_file.display()))?;
+        hosts.save(paths, None)?;
+    }
+
+    Ok(changed
+        .into_iter()
+        .map(|line_number| line_number + 1)
+        .collect())
+}
+
+fn hash_code(code: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    code.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn read_hosts(paths: &Paths) -> Result<String> {
+    let content = fs::read_to_string(&paths.hosts_file)?;
+    Ok(content)
+}
+
+fn hook_command(command: &str, content_name: &str, cwd: Option<&Path>) -> process::Command {
+    let mut hook = process::Command::new("sh");
+    hook.arg("-c")
+        .arg(command)
+        .env("SENKLOT_CONTENT", content_name);
+    if let Some(cwd) = cwd {
+        hook.current_dir(cwd);
+    }
+    hook
+}
+
+/// How long a before-hook may hold up the daemon, which waits for its verdict.
+const BEFORE_HOOK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Whether the hook vetoed the transition. A hook still running after
+/// `timeout` is killed and does not veto it.
+fn run_before_hook(
+    command: &str,
+    content_name: &str,
+    cwd: Option<&Path>,
+    timeout: time::Duration,
+) -> Result<bool> {
+    let mut child = hook_command(command, content_name, cwd).spawn()?;
+    match wait_timeout(&mut child, timeout)? {
+        Some(status) => Ok(status.code() == Some(VETO_EXIT_CODE)),
+        None => {
+            warn!(
+                "`{}` did not finish within {}s, so it does not veto",
+                command,
+                timeout.as_secs()
+            );
+            Ok(false)
+        }
+    }
+}
+
+pub fn run_hook(
+    command: &str,
+    content_name: &str,
+    cwd: Option<&Path>,
+) -> Result<process::ExitStatus> {
+    Ok(hook_command(command, content_name, cwd).status()?)
+}
+
+// The kernel truncates `/proc/<pid>/comm` to 15 bytes.
+const COMM_LENGTH: usize = 15;
+
+fn send_signal(signal: &ProcessSignal) -> Result<()> {
+    let process = signal.process.get(..COMM_LENGTH).unwrap_or(&signal.process);
+
+    for proc_entry in fs::read_dir("/proc")? {
+        let proc_entry = proc_entry?;
+        let pid = match proc_entry.file_name().to_str().map(str::parse) {
+            Some(Ok(pid)) => pid,
+            _ => continue,
+        };
+        // The process may have exited since the directory was listed.
+        let comm = match fs::read_to_string(proc_entry.path().join("comm")) {
+            Ok(comm) => comm,
+            Err(_) => continue,
+        };
+
+        if comm.trim_end() != process {
+            continue;
+        }
+
+        if unsafe { libc::kill(pid, signal.signal) } != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(anyhow!(
+                    "Unable to signal {} ({}): {}",
+                    signal.process,
+                    pid,
+                    error
+                ));
+            }
+        }
+    }
 
-fn excute_command(command: &str, content_name: &str) -> Result<()> {
-    process::Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .env("SENKLOT_CONTENT", content_name)
-        .spawn()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::temp_path;
+    use chrono::{Local, TimeZone};
+    use serde::de::DeserializeOwned;
+    use std::cell::{Cell, RefCell};
+    use std::io::prelude::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::rc::Rc;
+
+    /// A state writing a hosts file in its own temp directory, removed on drop.
+    struct Fixture {
+        dir: PathBuf,
+        config: Config,
+        state: State,
+        clock: Rc<FixedClock>,
+        /// Whether `set_hosts_writable(false)` was called, to undo it on drop.
+        made_unwritable: Cell<bool>,
+    }
+
+    impl Fixture {
+        fn new(name: &str, config: &str, hosts: &str) -> Fixture {
+            let dir = temp_path(name);
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+
+            let mut config: Config = toml::from_str(config).unwrap();
+            config.normalize_domains();
+            config.inherit_defaults();
+            config.paths.hosts_file = dir.join("hosts");
+            config.paths.state_file = dir.join("state");
+            fs::write(&config.paths.hosts_file, hosts).unwrap();
+
+            let clock = Rc::new(FixedClock::new(Local.ymd(2020, 6, 1).and_hms(12, 0, 0)));
+            let mut state = State::read_with_config(&config).unwrap();
+            state.clock = Box::new(clock.clone());
+
+            Fixture {
+                dir,
+                config,
+                state,
+                clock,
+                made_unwritable: Cell::new(false),
+            }
+        }
+
+        fn hosts(&self) -> String {
+            fs::read_to_string(&self.config.paths.hosts_file).unwrap()
+        }
+
+        /// Keeps even root from replacing the hosts file, as `chattr +i` does.
+        fn set_hosts_writable(&self, writable: bool) {
+            self.made_unwritable.set(!writable);
+            let flag = if writable { "-i" } else { "+i" };
+            let chattr = process::Command::new("chattr")
+                .arg(flag)
+                .arg(&self.config.paths.hosts_file)
+                .status();
+            if !chattr.is_ok_and(|status| status.success()) {
+                let mode = if writable { 0o755 } else { 0o555 };
+                fs::set_permissions(&self.dir, fs::Permissions::from_mode(mode)).unwrap();
+            }
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            if self.made_unwritable.get() {
+                self.set_hosts_writable(true);
+            }
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    const MANUAL_ENTRY: &str = "[sns]\ndomains = [\"example.com\"]\n";
+
+    #[test]
+    fn commit_backs_off_while_the_hosts_file_is_not_writable() {
+        let mut f = Fixture::new("hosts-not-writable", MANUAL_ENTRY, "127.0.0.1 localhost\n");
+        let entry = &f.config.entries["sns"];
+        f.set_hosts_writable(false);
+
+        assert!(f.state.set_locked("sns", entry));
+        let e = f.state.commit().unwrap_err();
+        assert!(e.is::<HostsNotWritable>(), "{:?}", e);
+        assert!(format!("{:?}", e).contains("chattr +i"));
+
+        // Retrying right away would only repeat the error.
+        assert_eq!(f.state.commit().unwrap(), Commit::Deferred);
+        assert!(!f.hosts().contains("example.com"));
+
+        f.set_hosts_writable(true);
+        f.clock.advance(min_hosts_backoff());
+        assert!(f.state.update(&f.config).is_ok());
+        assert!(f.hosts().contains("127.0.0.1 example.com"));
+        assert!(!f.state.pending_write);
+    }
+
+    const STATIC_ENTRY: &str = "[sns]\ndomains = [\"example.com\"]\nunlock = [\"06:00-07:00\"]\n";
+
+    #[test]
+    fn timed_unlock_overrides_the_schedule_until_it_expires() {
+        let mut f = Fixture::new("timed-unlock", STATIC_ENTRY, "");
+        let entry = &f.config.entries["sns"];
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+
+        f.state
+            .unlock_for("sns", Some(Duration::minutes(10)), entry, &None)
+            .unwrap();
+        f.clock.advance(Duration::minutes(5));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+        assert!(f.hosts().contains("# 127.0.0.1 example.com"));
+
+        f.clock.advance(Duration::minutes(5));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert!(!f.state.timed_unlocks.contains_key("sns"));
+    }
+
+    /// Records what it was asked to block, in place of nftables or dnsmasq.
+    #[derive(Default)]
+    struct FakeBlocker {
+        locked: Rc<RefCell<HashMap<String, bool>>>,
+        removed: Rc<Cell<bool>>,
+        fail: bool,
+    }
+
+    impl Blocker for FakeBlocker {
+        fn set_locked(&mut self, domain: &str, locked: bool) {
+            self.locked.borrow_mut().insert(domain.to_owned(), locked);
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            if self.fail {
+                return Err(anyhow!("Unable to block"));
+            }
+            Ok(())
+        }
+
+        fn is_locked(&self, domain: &str) -> bool {
+            self.locked.borrow().get(domain).cloned().unwrap_or(false)
+        }
+
+        fn remove(&mut self) -> Result<()> {
+            self.removed.set(true);
+            Ok(())
+        }
+    }
+
+    /// Puts a `FakeBlocker` in place of `backend`, returning what it blocks.
+    fn fake_backend(
+        f: &mut Fixture,
+        backend: Backend,
+        fail: bool,
+    ) -> Rc<RefCell<HashMap<String, bool>>> {
+        let blocker = FakeBlocker {
+            fail,
+            ..FakeBlocker::default()
+        };
+        let locked = blocker.locked.clone();
+        f.state.blockers.insert(backend, Box::new(blocker));
+        locked
+    }
+
+    const MIXED_ENTRIES: &str = r#"
+        [sns]
+        domains = ["example.com"]
+        unlock = ["06:00-07:00"]
+
+        [news]
+        domains = ["example.org"]
+        unlock = ["06:00-07:00"]
+        backend = "nft"
+    "#;
+
+    #[test]
+    fn entries_are_blocked_by_their_own_backends() {
+        let mut f = Fixture::new("mixed-backends", MIXED_ENTRIES, "");
+        let locked = fake_backend(&mut f, Backend::Nftables, false);
+        assert!(f.state.update(&f.config).is_ok());
+
+        assert!(f.hosts().contains("127.0.0.1 example.com"));
+        assert!(!f.hosts().contains("example.org"));
+        assert_eq!(locked.borrow().get("example.org"), Some(&true));
+        assert!(!locked.borrow().contains_key("example.com"));
+    }
+
+    #[test]
+    fn failing_backend_does_not_stop_the_others() {
+        let mut f = Fixture::new("failing-backend", MIXED_ENTRIES, "");
+        let locked = fake_backend(&mut f, Backend::Nftables, true);
+        f.state.is_locked.insert("sns".to_owned(), true);
+        f.state.is_locked.insert("news".to_owned(), true);
+
+        let e = f.state.commit().unwrap_err();
+        assert!(format!("{:#}", e).contains("Unable to block"));
+        assert!(f.hosts().contains("127.0.0.1 example.com"));
+        assert_eq!(locked.borrow().get("example.org"), Some(&true));
+    }
+
+    #[test]
+    fn changing_the_backend_removes_the_previous_one() {
+        let mut f = Fixture::new("remove-backend", STATIC_ENTRY, "");
+        let blocker = FakeBlocker::default();
+        let removed = blocker.removed.clone();
+        f.state.blockers.insert(Backend::Dnsmasq, Box::new(blocker));
+
+        f.state.reconfigure(&f.config);
+        assert!(removed.get());
+        assert!(f.state.blockers.is_empty());
+    }
+
+    #[test]
+    fn transitions_are_saved_whatever_the_backend() {
+        let config = format!("backend = \"dnsmasq\"\n{}", STATIC_ENTRY);
+        let mut f = Fixture::new("save-with-blocker", &config, "");
+        let locked = fake_backend(&mut f, Backend::Dnsmasq, false);
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(locked.borrow().get("example.com"), Some(&true));
+        assert_eq!(f.hosts(), "");
+
+        let saved = State::read_with_config(&f.config).unwrap();
+        assert_eq!(saved.is_locked.get("sns"), Some(&true));
+    }
+
+    /// A config whose `before_lock` hook counts its runs in `calls` and exits
+    /// with `exit_code`.
+    fn before_lock_config(calls: &Path, exit_code: i32, rest: &str) -> String {
+        format!(
+            "before_lock = \"echo >> {} && exit {}\"\n{}",
+            calls.display(),
+            exit_code,
+            rest
+        )
+    }
+
+    fn hook_calls(calls: &Path) -> usize {
+        fs::read_to_string(calls)
+            .map(|calls| calls.lines().count())
+            .unwrap_or(0)
+    }
+
+    /// Gives hooks run in the background a few seconds to reach `calls` calls.
+    fn wait_for_hook_calls(calls: &Path, count: usize) -> usize {
+        for _ in 0..50 {
+            if hook_calls(calls) >= count {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        hook_calls(calls)
+    }
+
+    #[test]
+    fn vetoed_lock_is_applied_after_max_vetoes() {
+        let calls = temp_path("veto-calls");
+        let _ = fs::remove_file(&calls);
+        let config = before_lock_config(
+            &calls,
+            VETO_EXIT_CODE,
+            &format!("max_vetoes = 2\n{}", STATIC_ENTRY),
+        );
+        let mut f = Fixture::new("veto", &config, "");
+
+        for vetoes in 1..=2 {
+            assert!(f.state.update(&f.config).is_ok());
+            assert_eq!(f.state.is_locked.get("sns"), None);
+            assert_eq!(hook_calls(&calls), vetoes);
+        }
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+
+        // No transition, so nothing to veto.
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(hook_calls(&calls), 2);
+        fs::remove_file(&calls).unwrap();
+    }
+
+    #[test]
+    fn before_hook_is_not_run_for_a_deferred_lock() {
+        let calls = temp_path("deferred-veto-calls");
+        let _ = fs::remove_file(&calls);
+        let entry = format!("{}lock_grace = \"10m\"\n", STATIC_ENTRY);
+        let config = before_lock_config(&calls, 0, &entry);
+        let mut f = Fixture::new("deferred-veto", &config, "");
+
+        f.clock.advance(Duration::minutes(18 * 60 + 30));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+
+        f.clock.advance(Duration::minutes(30));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+        assert_eq!(hook_calls(&calls), 0);
+
+        f.clock.advance(Duration::minutes(10));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert_eq!(hook_calls(&calls), 1);
+        fs::remove_file(&calls).unwrap();
+    }
+
+    #[test]
+    fn focus_lock_is_not_put_to_the_before_hook() {
+        let calls = temp_path("focus-veto-calls");
+        let _ = fs::remove_file(&calls);
+        let config = before_lock_config(&calls, VETO_EXIT_CODE, STATIC_ENTRY);
+        let mut f = Fixture::new("focus-veto", &config, "");
+
+        f.clock.advance(Duration::minutes(18 * 60 + 30));
+        assert!(f.state.update(&f.config).is_ok());
+        f.state.focus = Some(FocusSession {
+            until: f.clock.now() + Duration::minutes(10),
+            entries: vec!["sns".to_owned()],
+        });
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert_eq!(hook_calls(&calls), 0);
+    }
+
+    /// Calls a `request_*` method and decodes its answer.
+    fn answer<T: DeserializeOwned>(request: impl FnOnce(net::UnixStream) -> Result<()>) -> T {
+        let (daemon, mut client) = net::UnixStream::pair().unwrap();
+        request(daemon).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        bincode::deserialize(&response).unwrap()
+    }
+
+    fn unlock_request(name: &str) -> UnlockRequest {
+        UnlockRequest {
+            name: name.to_owned(),
+            reason: None,
+            code: None,
+            secs: None,
+        }
+    }
+
+    const DYNAMIC_ENTRY: &str =
+        "[sns]\ndomains = [\"example.com\"]\nperiod = \"30m\"\ncool_time = \"2h\"\n";
+
+    #[test]
+    fn cool_down_denied_unlock_is_counted() {
+        let mut f = Fixture::new("rejected-unlocks", DYNAMIC_ENTRY, "");
+        let (state, entry) = (&mut f.state, &f.config.entries["sns"]);
+        let request = unlock_request("sns");
+
+        let response = answer(|socket| state.request_unlock(socket, &request, entry, &None));
+        assert!(matches!(response, UnlockResponse::Success { .. }));
+        assert_eq!(state.rejected_unlocks.get("sns"), None);
+        state.lock("sns", entry, &None).unwrap();
+
+        for rejected in 1..=2 {
+            let response = answer(|socket| state.request_unlock(socket, &request, entry, &None));
+            assert!(matches!(response, UnlockResponse::Fail { .. }));
+            assert_eq!(state.rejected_unlocks.get("sns"), Some(&rejected));
+        }
+
+        let response: StatsResponse = answer(|socket| state.request_stats(socket));
+        assert_eq!(response.rejected_unlocks.get("sns"), Some(&2));
+    }
+
+    #[test]
+    fn state_file_round_trips() {
+        let mut f = Fixture::new("state-round-trip", DYNAMIC_ENTRY, "");
+        let now = f.clock.now();
+        f.state.last_unlocked.set("sns", now);
+        f.state.is_locked.set("sns", false);
+        f.state.rejected_unlocks.insert("sns".to_owned(), 3);
+
+        let state = parse_state(&f.state.export()).unwrap();
+        assert_eq!(state.last_unlocked.get("sns"), Some(&now));
+        assert_eq!(state.is_locked.get("sns"), Some(&false));
+        assert_eq!(state.rejected_unlocks.get("sns"), Some(&3));
+    }
+
+    #[test]
+    fn legacy_state_file_is_migrated() {
+        let now = Local.ymd(2020, 6, 1).and_hms(12, 0, 0);
+        let times: HashMap<_, _> = vec![("sns".to_owned(), now)].into_iter().collect();
+        let is_locked: HashMap<_, _> = vec![("sns".to_owned(), true)].into_iter().collect();
+        // The first release serialized the times with chrono's own serde impls.
+        let legacy = bincode::serialize(&(&times, &times, &is_locked)).unwrap();
+
+        let state = parse_state(&legacy).unwrap();
+        assert_eq!(state.last_unlocked.get("sns"), Some(&now));
+        assert_eq!(state.last_locked.get("sns"), Some(&now));
+        assert_eq!(state.is_locked.get("sns"), Some(&true));
+    }
+
+    #[test]
+    fn unreadable_state_file_is_discarded() {
+        assert!(parse_state(b"garbage").is_err());
+
+        let mut newer = STATE_MAGIC.to_vec();
+        newer.extend(bincode::serialize(&(STATE_VERSION + 1)).unwrap());
+        assert!(parse_state(&newer).is_err());
+
+        let f = Fixture::new("state-discarded", DYNAMIC_ENTRY, "");
+        fs::write(&f.config.paths.state_file, b"garbage").unwrap();
+        let state = State::read_with_config(&f.config).unwrap();
+        assert!(state.is_locked.is_empty());
+    }
+
+    #[test]
+    fn write_is_merged_onto_an_edit_made_while_it_was_prepared() {
+        let mut f = Fixture::new("interleaved-edit", MANUAL_ENTRY, "127.0.0.1 localhost\n");
+        let entry = &f.config.entries["sns"];
+        assert!(f.state.set_locked("sns", entry));
+
+        let edited = "127.0.0.1 localhost\n10.0.0.1 intranet\n";
+        let (mut blocker, _) = f.state.apply_to_hosts("127.0.0.1 localhost\n", false);
+        fs::write(&f.config.paths.hosts_file, edited).unwrap();
+        assert!(blocker.commit().unwrap_err().is::<HostsChanged>());
+        assert_eq!(f.hosts(), edited);
+        assert!(!f.config.paths.hosts_tmp().exists());
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Written);
+        let hosts = f.hosts();
+        assert!(hosts.contains("10.0.0.1 intranet"));
+        assert!(hosts.contains("127.0.0.1 example.com"));
+        assert_eq!(f.state.commit().unwrap(), Commit::Unchanged);
+    }
+
+    #[test]
+    fn ipv6_lines_are_released_once_block_ipv6_is_off() {
+        let hosts = "127.0.0.1 example.com # senklot:sns\n::1 example.com # senklot:sns\n";
+        let mut f = Fixture::new("stray-ipv6", MANUAL_ENTRY, hosts);
+        let entry = &f.config.entries["sns"];
+        f.state.is_locked.set("sns", true);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Written);
+        assert_eq!(
+            f.hosts(),
+            "127.0.0.1 example.com # senklot:sns\n# ::1 example.com # senklot:sns\n"
+        );
+
+        f.state.unlock("sns", entry, &None).unwrap();
+        assert_eq!(
+            f.hosts(),
+            "# 127.0.0.1 example.com # senklot:sns\n# ::1 example.com # senklot:sns\n"
+        );
+    }
+
+    #[test]
+    fn ipv6_line_unlocks_a_domain_locked_over_ipv4_only() {
+        let hosts = "# 127.0.0.1 example.com # senklot:sns\n::1 example.com # senklot:sns\n";
+        let mut f = Fixture::new("stray-ipv6-unlock", MANUAL_ENTRY, hosts);
+        f.state.is_locked.set("sns", false);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Written);
+        assert!(f.hosts().contains("# ::1 example.com"));
+    }
+
+    fn unlock_with_code(f: &mut Fixture, code: &str) -> UnlockResponse {
+        let (state, entry) = (&mut f.state, &f.config.entries["sns"]);
+        let request = UnlockRequest {
+            code: Some(code.to_owned()),
+            ..unlock_request("sns")
+        };
+        answer(|socket| state.request_unlock(socket, &request, entry, &None))
+    }
+
+    #[test]
+    fn unlock_requires_the_right_code() {
+        let mut f = Fixture::new("unlock-code", MANUAL_ENTRY, "");
+        f.state.is_locked.set("sns", true);
+        f.state.unlock_code_hash = Some(hash_code("12345678"));
+
+        let response = unlock_with_code(&mut f, "87654321");
+        assert!(matches!(response, UnlockResponse::Fail { .. }));
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+
+        f.clock.advance(Duration::seconds(2));
+        let response = unlock_with_code(&mut f, " 12345678\n");
+        assert!(matches!(response, UnlockResponse::Success { .. }));
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+    }
+
+    #[test]
+    fn wrong_codes_make_the_next_attempt_wait() {
+        let mut f = Fixture::new("unlock-code-wait", MANUAL_ENTRY, "");
+        f.state.is_locked.set("sns", true);
+        f.state.unlock_code_hash = Some(hash_code("12345678"));
+
+        for wait in &[2, 4, 8] {
+            assert!(f.state.check_unlock_code(Some("0")).is_err());
+            // Even the right code is refused until the wait is over.
+            f.clock.advance(Duration::seconds(wait - 1));
+            let e = f.state.check_unlock_code(Some("12345678")).unwrap_err();
+            assert!(e.to_string().starts_with("Too many wrong unlock codes"));
+            f.clock.advance(Duration::seconds(1));
+        }
+
+        assert!(f.state.check_unlock_code(Some("12345678")).is_ok());
+        assert_eq!(f.state.wrong_codes, 0);
+        assert!(f.state.check_unlock_code(Some("0")).is_err());
+        assert_eq!(
+            f.state.code_retry_at,
+            Some(f.clock.now() + Duration::seconds(2))
+        );
+    }
+
+    /// The domain, whether the line locks it, and the trailing comment.
+    fn parsed(line: &str) -> Option<(String, bool, Option<String>)> {
+        let (_, (domain, host, comment)) = host(line).ok()?;
+        Some((domain, matches!(host, Host::Locked(_)), comment))
+    }
+
+    #[test]
+    fn host_line_without_comment_is_parsed() {
+        let expected = Some(("example.com".to_owned(), true, None));
+        assert_eq!(parsed("127.0.0.1 example.com"), expected);
+        assert_eq!(parsed("127.0.0.1\texample.com"), expected);
+        assert_eq!(parsed("  127.0.0.1   example.com"), expected);
+    }
+
+    #[test]
+    fn trailing_comment_is_parsed() {
+        assert_eq!(
+            parsed("127.0.0.1 example.com # some note"),
+            Some(("example.com".to_owned(), true, Some("some note".to_owned())))
+        );
+        assert_eq!(
+            parsed("127.0.0.1 example.com#note"),
+            Some(("example.com".to_owned(), true, Some("note".to_owned())))
+        );
+        assert_eq!(
+            parsed("# 127.0.0.1 example.com # some note"),
+            Some((
+                "example.com".to_owned(),
+                false,
+                Some("some note".to_owned())
+            ))
+        );
+    }
+
+    #[test]
+    fn comment_is_split_into_note_and_owner() {
+        assert_eq!(split_comment("senklot:sns"), (None, Some("sns")));
+        assert_eq!(
+            split_comment("work # senklot:sns"),
+            (Some("work"), Some("sns"))
+        );
+        assert_eq!(
+            split_comment("a # b # senklot:sns"),
+            (Some("a # b"), Some("sns"))
+        );
+        assert_eq!(split_comment("work"), (Some("work"), None));
+        assert_eq!(split_comment("work # later"), (Some("work # later"), None));
+    }
+
+    #[test]
+    fn note_on_a_managed_line_survives_toggling() {
+        let hosts = "127.0.0.1 example.com # blocked for focus # senklot:sns\n";
+        let mut f = Fixture::new("managed-note", MANUAL_ENTRY, hosts);
+        let entry = &f.config.entries["sns"];
+        f.state.is_locked.set("sns", true);
+
+        f.state.unlock("sns", entry, &None).unwrap();
+        assert_eq!(
+            f.hosts(),
+            "# 127.0.0.1 example.com # blocked for focus # senklot:sns\n"
+        );
+        f.state.lock("sns", entry, &None).unwrap();
+        assert_eq!(f.hosts(), hosts);
+    }
+
+    #[test]
+    fn unmarked_lines_of_the_first_releases_are_adopted() {
+        let hosts = "127.0.0.1 localhost\n127.0.0.1 example.com\n";
+        let mut f = Fixture::new("legacy-lines", MANUAL_ENTRY, hosts);
+        f.state.is_locked.set("sns", false);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Written);
+        assert_eq!(
+            f.hosts(),
+            "127.0.0.1 localhost\n# 127.0.0.1 example.com # senklot:sns\n"
+        );
+    }
+
+    #[test]
+    fn other_unmarked_lines_are_left_alone() {
+        let hosts = "127.0.0.1 example.com # mine\n127.0.0.1 other.example\n";
+        let mut f = Fixture::new("user-lines", MANUAL_ENTRY, hosts);
+        f.state.is_locked.set("sns", true);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Written);
+        assert_eq!(
+            f.hosts(),
+            format!("{}127.0.0.1 example.com # senklot:sns\n", hosts)
+        );
+    }
+
+    #[test]
+    fn every_line_we_write_parses_back() {
+        let hosts = Hosts::parse("");
+        for &is_locked in &[true, false] {
+            for redirect_ip in &["127.0.0.1", "0.0.0.0", "::1"] {
+                for &note in &[None, Some("work")] {
+                    let line = hosts.host_line("example.com", is_locked, redirect_ip, "sns", note);
+                    let (domain, host, owner) = managed_host(&line).unwrap();
+                    assert_eq!(domain, "example.com", "{}", line);
+                    assert_eq!(matches!(host, Host::Locked(_)), is_locked, "{}", line);
+                    assert_eq!(host.is_ipv6(), redirect_ip.contains(':'), "{}", line);
+                    assert_eq!(owner, "sns", "{}", line);
+                    assert_eq!(line_note(&line).as_deref(), note, "{}", line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn commented_out_line_tolerates_spacing_around_the_hash() {
+        let expected = Some(("example.com".to_owned(), false, None));
+        assert_eq!(parsed("#127.0.0.1 example.com"), expected);
+        assert_eq!(parsed("#  127.0.0.1 example.com"), expected);
+        assert_eq!(parsed("  # 127.0.0.1 example.com"), expected);
+        assert_eq!(parsed("#\t127.0.0.1\texample.com"), expected);
+    }
+
+    #[test]
+    fn daemon_without_entries_does_not_read_the_hosts_file() {
+        let mut f = Fixture::new("no-entries", "", "");
+        fs::remove_file(&f.config.paths.hosts_file).unwrap();
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.commit().unwrap(), Commit::Unchanged);
+        assert!(!f.config.paths.hosts_file.exists());
+    }
+
+    #[test]
+    fn cool_down_counts_from_the_chosen_end_of_the_unlock() {
+        for (cooldown_from, unlockable_at_13_00) in &[("unlock_start", true), ("unlock_end", false)]
+        {
+            let config = format!(
+                "[sns]\ndomains = [\"example.com\"]\nperiod = \"30m\"\ncool_time = \"1h\"\ncooldown_from = \"{}\"\n",
+                cooldown_from
+            );
+            let mut f = Fixture::new(&format!("cooldown-{}", cooldown_from), &config, "");
+            let entry = &f.config.entries["sns"];
+            f.state.unlock("sns", entry, &None).unwrap();
+
+            f.clock.advance(Duration::minutes(30));
+            assert!(f.state.update(&f.config).is_ok());
+            assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+
+            f.clock.advance(Duration::minutes(30));
+            let entry = &f.config.entries["sns"];
+            assert_eq!(
+                f.state.unlock("sns", entry, &None).is_ok(),
+                *unlockable_at_13_00,
+                "{}",
+                cooldown_from
+            );
+            f.clock.advance(Duration::minutes(30));
+            assert!(f.state.unlock("sns", entry, &None).is_ok());
+        }
+    }
+
+    #[test]
+    fn write_over_max_hosts_lines_is_refused() {
+        let config = "max_hosts_lines = 1\n[sns]\ndomains = [\"example.com\", \"example.org\"]\n";
+        let mut f = Fixture::new("max-hosts-lines", config, "127.0.0.1 localhost\n");
+        f.state.is_locked.set("sns", true);
+
+        let e = f.state.commit().unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Refusing to write 2 managed lines"));
+        let hosts_file = f.config.paths.hosts_file.display().to_string();
+        assert!(e.to_string().contains(&hosts_file), "{}", e);
+        assert_eq!(f.hosts(), "127.0.0.1 localhost\n");
+    }
+
+    fn challenge(f: &mut Fixture) -> u64 {
+        let (state, entry) = (&mut f.state, &f.config.entries["sns"]);
+        let request = unlock_request("sns");
+        match answer(|socket| state.request_unlock(socket, &request, entry, &None)) {
+            UnlockResponse::Challenge { nonce, wait_secs } => {
+                assert_eq!(wait_secs, 60);
+                nonce
+            }
+            _ => panic!("expected a challenge"),
+        }
+    }
+
+    fn complete_challenge(f: &mut Fixture, nonce: u64) -> UnlockResponse {
+        let (state, entry) = (&mut f.state, &f.config.entries["sns"]);
+        answer(|socket| state.request_complete_unlock(socket, "sns", nonce, None, entry, &None))
+    }
+
+    #[test]
+    fn unlock_delay_is_a_challenge_completed_after_the_wait() {
+        let config = format!("{}unlock_delay = \"1m\"\n", MANUAL_ENTRY);
+        let mut f = Fixture::new("challenge", &config, "");
+        f.state.is_locked.set("sns", true);
+
+        let nonce = challenge(&mut f);
+        f.clock.advance(Duration::seconds(59));
+        match complete_challenge(&mut f, nonce) {
+            UnlockResponse::Fail { cause, .. } => assert!(cause.contains("too early")),
+            _ => panic!("expected the early completion to fail"),
+        }
+        // A challenge is good for one attempt only.
+        assert!(matches!(
+            complete_challenge(&mut f, nonce),
+            UnlockResponse::Fail { .. }
+        ));
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+
+        let nonce = challenge(&mut f);
+        f.clock.advance(Duration::seconds(60));
+        assert!(matches!(
+            complete_challenge(&mut f, nonce.wrapping_add(1)),
+            UnlockResponse::Fail { .. }
+        ));
+        let nonce = challenge(&mut f);
+        f.clock.advance(Duration::seconds(60));
+        assert!(matches!(
+            complete_challenge(&mut f, nonce),
+            UnlockResponse::Success { .. }
+        ));
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+    }
+
+    #[test]
+    fn entry_redirect_ip_overrides_the_global_one() {
+        let config = "redirect = \"127.0.0.2\"\n[news]\ndomains = [\"example.org\"]\n[sns]\ndomains = [\"example.com\"]\nredirect_ip = \"0.0.0.0\"\n";
+        let mut f = Fixture::new("entry-redirect", config, "");
+        f.state.is_locked.set("news", true);
+        f.state.is_locked.set("sns", true);
+
+        f.state.commit().unwrap();
+        assert!(f.hosts().contains("0.0.0.0 example.com # senklot:sns\n"));
+        assert!(f.hosts().contains("127.0.0.2 example.org # senklot:news\n"));
+    }
+
+    #[test]
+    fn release_unlocks_every_domain_but_keeps_the_state() {
+        let mut f = Fixture::new("release", MANUAL_ENTRY, "127.0.0.1 localhost\n");
+        f.state.is_locked.set("sns", true);
+        f.state.commit().unwrap();
+        assert!(f.hosts().contains("\n127.0.0.1 example.com"));
+
+        f.state.release().unwrap();
+        assert_eq!(
+            f.hosts(),
+            "127.0.0.1 localhost\n# 127.0.0.1 example.com # senklot:sns\n"
+        );
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+    }
+
+    #[test]
+    fn shared_domain_stays_locked_while_any_entry_is_locked() {
+        let config = "[video]\ndomains = [\"example.com\"]\n[sns]\ndomains = [\"example.com\"]\n";
+        let mut f = Fixture::new("shared-domain", config, "");
+        f.state.is_locked.set("sns", false);
+        f.state.is_locked.set("video", true);
+        f.state.commit().unwrap();
+        assert_eq!(f.hosts(), "127.0.0.1 example.com # senklot:sns\n");
+
+        f.state.is_locked.set("video", false);
+        f.state.commit().unwrap();
+        assert_eq!(f.hosts(), "# 127.0.0.1 example.com # senklot:sns\n");
+    }
+
+    #[test]
+    fn unlocked_entry_is_nagged_every_nag_after() {
+        let config = format!("{}nag_after = \"1h\"\n", MANUAL_ENTRY);
+        let mut f = Fixture::new("nag", &config, "");
+        let entry = &f.config.entries["sns"];
+        f.state.unlock("sns", entry, &None).unwrap();
+        let start = f.clock.now();
+
+        for (minutes, nagged_at) in &[(59, None), (60, Some(60)), (90, Some(60)), (120, Some(120))]
+        {
+            f.clock
+                .advance(start + Duration::minutes(*minutes) - f.clock.now());
+            assert!(f.state.update(&f.config).is_ok());
+            assert_eq!(
+                f.state.last_nagged.get("sns"),
+                nagged_at.map(|m| start + Duration::minutes(m)).as_ref(),
+                "after {} minutes",
+                minutes
+            );
+        }
+
+        let entry = &f.config.entries["sns"];
+        f.state.lock("sns", entry, &None).unwrap();
+        assert!(!f.state.last_nagged.contains_key("sns"));
+    }
+
+    #[test]
+    fn update_leaves_manual_entries_alone() {
+        let mut f = Fixture::new("manual", MANUAL_ENTRY, "");
+        assert!(f.state.update(&f.config).is_ok());
+        assert!(!f.state.is_locked.contains_key("sns"));
+
+        for &is_locked in &[true, false] {
+            f.state.is_locked.set("sns", is_locked);
+            f.clock.advance(Duration::days(1));
+            assert!(f.state.update(&f.config).is_ok());
+            assert_eq!(f.state.is_locked.get("sns"), Some(&is_locked));
+        }
+    }
+
+    #[test]
+    fn tick_writes_the_hosts_file_once_for_all_transitions() {
+        let writes = temp_path("batched-writes");
+        let _ = fs::remove_file(&writes);
+        let config = format!(
+            "flush_dns = true\nflush_dns_command = \"echo >> {}\"\n{}[news]\ndomains = [\"example.org\"]\nunlock = [\"06:00-07:00\"]\n",
+            writes.display(),
+            STATIC_ENTRY
+        );
+        let mut f = Fixture::new("batched", &config, "");
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert_eq!(f.state.is_locked.get("news"), Some(&true));
+        assert_eq!(wait_for_hook_calls(&writes, 1), 1);
+        fs::remove_file(&writes).unwrap();
+    }
+
+    #[test]
+    fn initial_state_applies_to_entries_never_seen_before() {
+        for (initial_state, is_locked) in
+            &[("schedule", true), ("locked", true), ("unlocked", false)]
+        {
+            let config = format!("initial_state = \"{}\"\n{}", initial_state, STATIC_ENTRY);
+            let mut f = Fixture::new(&format!("initial-{}", initial_state), &config, "");
+            assert!(f.state.update(&f.config).is_ok());
+            assert_eq!(
+                f.state.is_locked.get("sns"),
+                Some(is_locked),
+                "{}",
+                initial_state
+            );
+        }
+
+        // Known entries follow their schedule.
+        let config = format!("initial_state = \"unlocked\"\n{}", STATIC_ENTRY);
+        let mut f = Fixture::new("initial-known", &config, "");
+        f.state.is_locked.set("sns", false);
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+    }
+
+    #[test]
+    fn dynamic_entry_never_unlocked_is_locked() {
+        let mut f = Fixture::new("dynamic-fresh", DYNAMIC_ENTRY, "");
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert!(f.hosts().contains("127.0.0.1 example.com"));
+
+        // Unlocked for its period, then locked again.
+        let entry = &f.config.entries["sns"];
+        f.state.unlock("sns", entry, &None).unwrap();
+        f.clock.advance(Duration::minutes(29));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+        f.clock.advance(Duration::minutes(1));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+    }
+
+    #[test]
+    fn focus_session_locks_tagged_entries_until_it_ends() {
+        let config =
+            "[sns]\ndomains = [\"example.com\"]\nunlock = [\"11:00-14:00\"]\ntags = [\"focus\"]\n\
+                      [news]\ndomains = [\"example.org\"]\nunlock = [\"11:00-14:00\"]\n";
+        let mut f = Fixture::new("focus", config, "");
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+
+        let (state, config) = (&mut f.state, &f.config);
+        let response: FocusResponse =
+            answer(|socket| state.request_focus(socket, Duration::hours(1), Some("focus"), config));
+        assert_eq!(response.entries, ["sns"]);
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert_eq!(f.state.is_locked.get("news"), Some(&false));
+        let entry = &f.config.entries["sns"];
+        assert!(f.state.unlock("sns", entry, &None).is_err());
+
+        let mut restarted = State::read_with_config(&f.config).unwrap();
+        assert_eq!(restarted.focus_until("sns"), Some(response.until));
+        restarted.clock = Box::new(f.clock.clone());
+        f.clock.advance(Duration::hours(1));
+        assert!(restarted.update(&f.config).is_ok());
+        assert!(restarted.focus.is_none());
+        assert_eq!(restarted.is_locked.get("sns"), Some(&false));
+    }
+
+    #[test]
+    fn unlock_scheduled_opens_only_entries_in_their_window() {
+        let config = "[news]\ndomains = [\"example.org\"]\nunlock = [\"06:00-07:00\"]\n\
+                      [sns]\ndomains = [\"example.com\"]\nunlock = [\"11:00-14:00\"]\n\
+                      [video]\ndomains = [\"example.net\"]\nunlock = [\"11:00-14:00\"]\n";
+        let mut f = Fixture::new("unlock-scheduled", config, "");
+        f.state.is_locked.set("news", true);
+        f.state.is_locked.set("sns", true);
+        f.state.is_locked.set("video", false);
+
+        let (state, config) = (&mut f.state, &f.config);
+        let response: UnlockScheduledResponse =
+            answer(|socket| state.request_unlock_scheduled(socket, config));
+        assert_eq!(response.results, [("sns".to_owned(), None)]);
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+        assert_eq!(f.state.is_locked.get("news"), Some(&true));
+    }
+
+    #[test]
+    fn hooks_run_in_hook_cwd() {
+        let dir = temp_path("hook-cwd");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let config = format!(
+            "after_unlock = \"pwd > cwd\"\nhook_cwd = \"{}\"\n{}",
+            dir.display(),
+            MANUAL_ENTRY
+        );
+        let mut f = Fixture::new("hook-cwd-state", &config, "");
+        f.state.is_locked.set("sns", true);
+
+        let entry = &f.config.entries["sns"];
+        f.state
+            .unlock("sns", entry, &f.config.after_unlock)
+            .unwrap();
+        let cwd = dir.join("cwd");
+        for _ in 0..50 {
+            if fs::read_to_string(&cwd).is_ok_and(|cwd| cwd.ends_with('\n')) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(
+            fs::read_to_string(&cwd).unwrap().trim(),
+            dir.to_str().unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn why(f: &Fixture) -> String {
+        let entry = &f.config.entries["sns"];
+        let response: WhyResponse = answer(|socket| f.state.request_why(socket, "sns", entry));
+        response.reason
+    }
+
+    #[test]
+    fn why_names_the_controlling_reason() {
+        let config = "[sns]\ndomains = [\"example.com\"]\nunlock = [\"12:00-13:00\"]\n";
+        let mut f = Fixture::new("why", config, "");
+        assert_eq!(why(&f), "senklot has not managed it yet");
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(why(&f), "scheduled window 12:00-13:00 active");
+
+        f.clock.advance(Duration::hours(1));
+        assert_eq!(
+            why(&f),
+            "outside every scheduled window; it will be locked on the next tick unless vetoed"
+        );
+
+        assert!(f.state.update(&f.config).is_ok());
+        let (state, entry) = (&mut f.state, &f.config.entries["sns"]);
+        state
+            .unlock_for("sns", Some(Duration::minutes(30)), entry, &None)
+            .unwrap();
+        assert_eq!(why(&f), "unlocked on request until 2020-06-01 13:30");
+    }
+
+    #[test]
+    fn format_hosts_tidies_managed_lines_only() {
+        let hosts = "127.0.0.1   localhost  # loopback\n\
+                     127.0.0.1\texample.com   #senklot:sns\n\
+                     #127.0.0.1 example.org # work # senklot:news\n\
+                     # a comment\n";
+        let f = Fixture::new("format-hosts", "", hosts);
+
+        assert_eq!(format_hosts(&f.config.paths).unwrap(), [2, 3]);
+        assert_eq!(
+            f.hosts(),
+            "127.0.0.1   localhost  # loopback\n\
+             127.0.0.1 example.com # senklot:sns\n\
+             # 127.0.0.1 example.org # work # senklot:news\n\
+             # a comment\n"
+        );
+        assert_eq!(
+            fs::read_to_string(f.config.paths.hosts_backup()).unwrap(),
+            hosts
+        );
+
+        assert!(format_hosts(&f.config.paths).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lock_grace_defers_a_scheduled_lock_until_it_ends() {
+        let entry = format!("{}lock_grace = \"10m\"\n", STATIC_ENTRY);
+        let mut f = Fixture::new("lock-grace", &entry, "");
+
+        f.clock.advance(Duration::minutes(18 * 60 + 30));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+
+        f.clock.advance(Duration::minutes(30));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+        assert!(f.state.deferred_locks.contains_key("sns"));
+
+        f.clock.advance(Duration::minutes(9));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&false));
+
+        f.clock.advance(Duration::minutes(1));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert!(f.state.deferred_locks.is_empty());
+    }
+
+    #[test]
+    fn dns_cache_is_flushed_only_after_a_write() {
+        let flushes = temp_path("flushes");
+        let _ = fs::remove_file(&flushes);
+        let config = format!(
+            "flush_dns = true\nflush_dns_command = \"echo >> {}\"\n{}",
+            flushes.display(),
+            STATIC_ENTRY
+        );
+        let mut f = Fixture::new("flush-dns", &config, "");
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(wait_for_hook_calls(&flushes, 1), 1);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Unchanged);
+        f.clock.advance(Duration::minutes(18 * 60 + 30));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(wait_for_hook_calls(&flushes, 2), 2);
+        fs::remove_file(&flushes).unwrap();
+    }
+
+    #[test]
+    fn dns_cache_is_not_flushed_unless_enabled() {
+        let flushes = temp_path("no-flushes");
+        let _ = fs::remove_file(&flushes);
+        let config = format!(
+            "flush_dns_command = \"echo >> {}\"\n{}",
+            flushes.display(),
+            STATIC_ENTRY
+        );
+        let mut f = Fixture::new("no-flush-dns", &config, "");
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert_eq!(hook_calls(&flushes), 0);
+    }
+
+    #[test]
+    fn trailing_newline_is_kept_as_it_was() {
+        for (name, hosts) in &[
+            ("with-newline", "127.0.0.1 localhost\n"),
+            ("without-newline", "127.0.0.1 localhost"),
+        ] {
+            let mut f = Fixture::new(name, STATIC_ENTRY, hosts);
+            assert!(f.state.update(&f.config).is_ok());
+            let written = f.hosts();
+            assert!(
+                written.starts_with("127.0.0.1 localhost\n"),
+                "{:?}",
+                written
+            );
+            assert!(written.contains("example.com"), "{:?}", written);
+            assert_eq!(
+                written.ends_with('\n'),
+                hosts.ends_with('\n'),
+                "{:?}",
+                written
+            );
+        }
+    }
+
+    #[test]
+    fn crlf_line_endings_are_kept() {
+        let mut f = Fixture::new(
+            "crlf",
+            STATIC_ENTRY,
+            "127.0.0.1 localhost\r\n::1 localhost\r\n",
+        );
+        assert!(f.state.update(&f.config).is_ok());
+
+        let written = f.hosts();
+        assert!(written.contains("example.com"), "{:?}", written);
+        assert_eq!(written.matches('\n').count(), 3, "{:?}", written);
+        assert_eq!(written.matches("\r\n").count(), 3, "{:?}", written);
+    }
+
+    #[test]
+    fn domain_added_by_a_commit_is_not_added_again() {
+        let mut f = Fixture::new("added-once", STATIC_ENTRY, "127.0.0.1 localhost\n");
+        assert!(f.state.update(&f.config).is_ok());
+        let written = f.hosts();
+        assert_eq!(written.matches("example.com").count(), 1, "{:?}", written);
+
+        assert!(f.state.commit().is_ok());
+        f.clock.advance(Duration::minutes(18 * 60 + 30));
+        assert!(f.state.update(&f.config).is_ok());
+        f.clock.advance(Duration::hours(1));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.hosts(), written);
+    }
+
+    #[test]
+    fn own_write_is_told_apart_from_an_external_edit() {
+        let mut f = Fixture::new("own-write", STATIC_ENTRY, "127.0.0.1 localhost\n");
+        assert!(!f.state.hosts_are_own_write());
+
+        assert!(f.state.update(&f.config).is_ok());
+        assert!(f.state.hosts_are_own_write());
+
+        let edited = format!("{}127.0.0.1 example.net\n", f.hosts());
+        fs::write(&f.config.paths.hosts_file, edited).unwrap();
+        assert!(!f.state.hosts_are_own_write());
+    }
+
+    #[test]
+    fn hooks_of_a_deferred_write_run_once_it_lands() {
+        let calls = temp_path("deferred-hook-calls");
+        let _ = fs::remove_file(&calls);
+        let config = format!(
+            "after_lock = \"echo >> {}\"\n{}",
+            calls.display(),
+            STATIC_ENTRY
+        );
+        let mut f = Fixture::new("deferred-hooks", &config, "127.0.0.1 localhost\n");
+        f.set_hosts_writable(false);
+
+        assert!(f.state.update(&f.config).is_err());
+        assert_eq!(f.state.is_locked.get("sns"), Some(&true));
+        assert!(f.state.update(&f.config).is_ok());
+        assert_eq!(f.state.pending_transitions, [("sns".to_owned(), true)]);
+        assert!(!f.config.paths.state_file.exists());
+
+        f.set_hosts_writable(true);
+        f.clock.advance(min_hosts_backoff());
+        assert!(f.state.update(&f.config).is_ok());
+        assert!(f.hosts().contains("127.0.0.1 example.com"));
+        assert!(f.state.pending_transitions.is_empty());
+        assert!(f.config.paths.state_file.exists());
+        assert_eq!(wait_for_hook_calls(&calls, 1), 1);
+        fs::remove_file(&calls).unwrap();
+    }
+
+    #[test]
+    fn transition_undone_before_its_write_lands_runs_no_hooks() {
+        let mut f = Fixture::new("undone-transition", MANUAL_ENTRY, "");
+        f.state.record_transition("sns", true);
+        f.state.record_transition("sns", false);
+        assert!(f.state.pending_transitions.is_empty());
+    }
+
+    #[test]
+    fn rotated_unlock_code_is_written_to_an_owner_only_file() {
+        let mut f = Fixture::new("unlock-code-file", MANUAL_ENTRY, "");
+        f.state.is_locked.set("sns", true);
+        f.state.rotate_unlock_code().unwrap();
+
+        let path = f.config.paths.unlock_code_file();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let code = fs::read_to_string(&path).unwrap();
+        let response = unlock_with_code(&mut f, &code);
+        assert!(matches!(response, UnlockResponse::Success { .. }));
+    }
+
+    #[test]
+    fn status_lists_the_tags_of_each_entry() {
+        let config = "[sns]\ndomains = [\"example.com\"]\ntags = [\"focus\", \"evening\"]\n\
+                      [news]\ndomains = [\"example.org\"]\n";
+        let f = Fixture::new("status-tags", config, "");
+
+        let response: StatusResponse = answer(|socket| f.state.request_status(socket, &f.config));
+        let tags: Vec<_> = response
+            .entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.tags.clone()))
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                ("news", vec![]),
+                ("sns", vec!["focus".to_owned(), "evening".to_owned()])
+            ]
+        );
+    }
+
+    #[test]
+    fn domains_match_whatever_their_case() {
+        let config = "[sns]\ndomains = [\"YouTube.com\"]\n";
+        let hosts = "127.0.0.1 localhost\n# 127.0.0.1 youtube.COM # senklot:sns\n";
+        let mut f = Fixture::new("mixed-case", config, hosts);
+        let entry = &f.config.entries["sns"];
+        f.state.is_locked.set("sns", false);
+
+        assert_eq!(f.state.commit().unwrap(), Commit::Unchanged);
+        f.state.lock("sns", entry, &None).unwrap();
+        let written = f.hosts();
+        assert_eq!(written.matches("127.0.0.1").count(), 2, "{}", written);
+        assert!(
+            written.contains("\n127.0.0.1 youtube.com # senklot:sns\n"),
+            "{}",
+            written
+        );
+    }
+
+    #[test]
+    fn hung_before_hook_is_killed_and_does_not_veto() {
+        let started = std::time::Instant::now();
+        let vetoed = run_before_hook(
+            "sleep 10; exit 100",
+            "sns",
+            None,
+            std::time::Duration::from_millis(200),
+        );
+        assert!(!vetoed.unwrap());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        let vetoed = run_before_hook("exit 100", "sns", None, BEFORE_HOOK_TIMEOUT);
+        assert!(vetoed.unwrap());
+    }
+
+    #[test]
+    fn remaining_tells_apart_locked_timed_and_indefinite_unlocks() {
+        let config = format!("{}[games]\ndomains = [\"example.net\"]\n", STATIC_ENTRY);
+        let mut f = Fixture::new("remaining", &config, "");
+        assert!(f.state.update(&f.config).is_ok());
+        f.state.is_locked.set("games", false);
+
+        let remaining = |f: &Fixture, name: &str| -> RemainingResponse {
+            answer(|socket| {
+                f.state
+                    .request_remaining(socket, name, &f.config.entries[name])
+            })
+        };
+        assert!(matches!(remaining(&f, "sns"), RemainingResponse::Locked));
+        assert!(matches!(
+            remaining(&f, "games"),
+            RemainingResponse::UnlockedIndefinitely
+        ));
+
+        let until = f.clock.now() + Duration::minutes(10);
+        f.state.is_locked.set("sns", false);
+        f.state.timed_unlocks.set("sns", until);
+        assert!(matches!(
+            remaining(&f, "sns"),
+            RemainingResponse::Unlocked { locked_at } if locked_at == until
+        ));
+    }
+}
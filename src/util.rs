@@ -1,8 +1,23 @@
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use log::{error, warn};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::CString;
 use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::os::unix::net;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time;
+
+use crate::clock::*;
+use crate::config::LocalTime;
 
 pub trait MutDict<V> {
     fn set(&mut self, key: &str, value: V);
@@ -26,19 +41,11 @@ pub trait Optional<T> {
 }
 
 pub trait OptionCond<T> {
-    fn and_if_flat<F: FnOnce(T) -> Option<bool>>(self, pred: F) -> bool;
-    fn or_if_flat<F: FnOnce(T) -> Option<bool>>(self, pred: F) -> bool;
     fn and_if<F: FnOnce(T) -> bool>(self, pred: F) -> bool;
     fn or_if<F: FnOnce(T) -> bool>(self, pred: F) -> bool;
 }
 
 impl<T, O: Optional<T>> OptionCond<T> for O {
-    fn and_if_flat<F: FnOnce(T) -> Option<bool>>(self, pred: F) -> bool {
-        self.into_option().and_then(pred).unwrap_or(false)
-    }
-    fn or_if_flat<F: FnOnce(T) -> Option<bool>>(self, pred: F) -> bool {
-        self.into_option().and_then(pred).unwrap_or(true)
-    }
     fn and_if<F: FnOnce(T) -> bool>(self, pred: F) -> bool {
         self.into_option().map(pred).unwrap_or(false)
     }
@@ -59,27 +66,134 @@ impl<T, E> Optional<T> for Result<T, E> {
     }
 }
 
+const BIND_ATTEMPTS: usize = 3;
+const BIND_RETRY_DELAY: time::Duration = time::Duration::from_millis(500);
+
+pub fn random_u64() -> Result<u64> {
+    let mut bytes = [0; 8];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(u64::from_ne_bytes(bytes))
+}
+
+/// Creates or truncates a file only its owner can read, for the state file and
+/// the daemon logs, which reveal when each entry was used.
+pub fn create_private<P: AsRef<Path>>(path: P) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    // `mode` only applies on creation, so tighten files left by older versions too.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+pub fn write_private<P: AsRef<Path>>(path: P, contents: &[u8]) -> Result<()> {
+    create_private(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// Replaces `path` by writing `tmp` and renaming it over `path`, so readers
+/// see either the old or the new contents. `tmp` must be on the same filesystem.
+///
+/// With `expected`, leaves `path` alone and returns false if it no longer holds
+/// `expected`, which is checked right before the rename.
+pub fn replace_file(
+    path: &Path,
+    tmp: &Path,
+    contents: &[u8],
+    expected: Option<&[u8]>,
+) -> io::Result<bool> {
+    let metadata = fs::metadata(path)?;
+
+    let result = write_like(tmp, contents, &metadata).and_then(|()| match expected {
+        Some(expected) if fs::read(path)? != expected => Ok(false),
+        _ => fs::rename(tmp, path).map(|()| true),
+    });
+    if !result.as_ref().is_ok_and(|replaced| *replaced) {
+        let _ = fs::remove_file(tmp);
+    }
+    result
+}
+
+fn write_like(path: &Path, contents: &[u8], metadata: &fs::Metadata) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::set_permissions(path, metadata.permissions())?;
+    chown(path, metadata.uid(), metadata.gid())
+}
+
+fn chown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
 pub struct SocketPath {
     path: PathBuf,
 }
 
 impl SocketPath {
     pub fn bind<P: AsRef<Path>>(path: P) -> Result<(SocketPath, net::UnixListener)> {
-        let _ = fs::remove_file(path.as_ref());
-        let listener = net::UnixListener::bind(path.as_ref())?;
-        Ok((
-            SocketPath {
-                path: path.as_ref().to_path_buf(),
-            },
-            listener,
-        ))
+        let path = path.as_ref();
+
+        if net::UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "Another senklot daemon is already listening on {}",
+                path.display()
+            ));
+        }
+
+        let mut attempt = 1;
+        loop {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() == ErrorKind::PermissionDenied {
+                    warn!("Permission denied on removing {}", path.display());
+                }
+            }
+
+            let e = match net::UnixListener::bind(path) {
+                Ok(listener) => {
+                    return Ok((
+                        SocketPath {
+                            path: path.to_path_buf(),
+                        },
+                        listener,
+                    ))
+                }
+                Err(e) => e,
+            };
+
+            let cause = match e.kind() {
+                ErrorKind::PermissionDenied => {
+                    format!("permission denied on socket path {}", path.display())
+                }
+                ErrorKind::AddrInUse => format!("address {} is in use", path.display()),
+                _ => format!("{}", e),
+            };
+            warn!(
+                "Unable to bind the socket (attempt {}/{}): {}",
+                attempt, BIND_ATTEMPTS, cause
+            );
+
+            if attempt == BIND_ATTEMPTS {
+                return Err(anyhow!("Unable to bind the socket: {}", cause));
+            }
+            attempt += 1;
+            thread::sleep(BIND_RETRY_DELAY);
+        }
     }
 
     pub fn allow_write(&self) -> Result<()> {
         let mut permissions = fs::metadata(&self.path)?.permissions();
-        permissions.set_readonly(false);
-        let ok = fs::set_permissions(&self.path, permissions)?;
-        Ok(ok)
+        permissions.set_mode(permissions.mode() | 0o222);
+        fs::set_permissions(&self.path, permissions)?;
+        Ok(())
     }
 }
 
@@ -88,3 +202,589 @@ impl Drop for SocketPath {
         fs::remove_file(&self.path).expect("Unable to remove the socket");
     }
 }
+
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes().max(0);
+
+    if minutes < 60 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}h{:02}m", minutes / 60, minutes % 60)
+    }
+}
+
+const DIFF_CONTEXT: usize = 3;
+
+/// The largest LCS table `diff_ops` builds; bigger changes become one hunk.
+const MAX_LCS_CELLS: usize = 1 << 20;
+
+/// A unified diff from `old` to `new`, empty if they have the same lines.
+pub fn unified_diff(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let ops = diff_ops(&old, &new);
+
+    let mut diff = String::new();
+    let mut k = 0;
+    while k < ops.len() {
+        if ops[k].0 == ' ' {
+            k += 1;
+            continue;
+        }
+
+        // Grow the hunk until the changes are separated by more than twice the context.
+        let start = k.saturating_sub(DIFF_CONTEXT);
+        let mut last_change = k;
+        while k < ops.len() && k - last_change <= 2 * DIFF_CONTEXT {
+            if ops[k].0 != ' ' {
+                last_change = k;
+            }
+            k += 1;
+        }
+        let end = (last_change + DIFF_CONTEXT + 1).min(ops.len());
+        let hunk = &ops[start..end];
+
+        let old_len = hunk.iter().filter(|(tag, _, _)| *tag != '+').count();
+        let new_len = hunk.iter().filter(|(tag, _, _)| *tag != '-').count();
+        let (_, old_start, new_start) = hunk[0];
+        diff += &format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + (old_len > 0) as usize,
+            old_len,
+            new_start + (new_len > 0) as usize,
+            new_len
+        );
+        for &(tag, i, j) in hunk {
+            let line = if tag == '+' { new[j] } else { old[i] };
+            diff += &format!("{}{}\n", tag, line);
+        }
+        k = end;
+    }
+
+    if diff.is_empty() {
+        diff
+    } else {
+        format!("--- {}\n+++ {}\n{}", old_name, new_name, diff)
+    }
+}
+
+/// Tags every line as kept (` `), removed (`-`) or added (`+`), with its index
+/// in `old` and in `new`.
+fn diff_ops(old: &[&str], new: &[&str]) -> Vec<(char, usize, usize)> {
+    // Only the lines between the common prefix and suffix need comparing,
+    // which for a hosts file is usually a handful.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+
+    let mut ops: Vec<_> = (0..prefix).map(|i| (' ', i, i)).collect();
+    let (old_mid, new_mid) = (&old[prefix..old_end], &new[prefix..new_end]);
+    if (old_mid.len() + 1) * (new_mid.len() + 1) <= MAX_LCS_CELLS {
+        ops.extend(
+            lcs_ops(old_mid, new_mid)
+                .into_iter()
+                .map(|(tag, i, j)| (tag, prefix + i, prefix + j)),
+        );
+    } else {
+        ops.extend((prefix..old_end).map(|i| ('-', i, prefix)));
+        ops.extend((prefix..new_end).map(|j| ('+', old_end, j)));
+    }
+    ops.extend((0..suffix).map(|k| (' ', old_end + k, new_end + k)));
+    ops
+}
+
+fn lcs_ops(old: &[&str], new: &[&str]) -> Vec<(char, usize, usize)> {
+    // lcs[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', i, j));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            ops.push(('+', i, j));
+            j += 1;
+        } else {
+            ops.push(('-', i, j));
+            i += 1;
+        }
+    }
+    ops
+}
+
+/// Waits for `child` for at most `timeout`, killing it once that has passed.
+/// Returns `None` if it had to be killed.
+pub fn wait_timeout(
+    child: &mut process::Child,
+    timeout: time::Duration,
+) -> io::Result<Option<process::ExitStatus>> {
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if time::Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+/// Runs at most `max` hook processes at once, queueing the rest.
+#[derive(Default)]
+pub struct HookPool {
+    max: Option<usize>,
+    running: Vec<process::Child>,
+    queue: VecDeque<process::Command>,
+}
+
+impl HookPool {
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+    }
+
+    pub fn spawn(&mut self, command: process::Command) -> io::Result<()> {
+        self.queue.push_back(command);
+        self.poll()
+    }
+
+    /// Reaps finished hooks and starts queued ones in their place.
+    pub fn poll(&mut self) -> io::Result<()> {
+        self.running = self
+            .running
+            .drain(..)
+            .filter_map(|mut child| match child.try_wait() {
+                Ok(Some(_)) => None,
+                _ => Some(child),
+            })
+            .collect();
+
+        while self.max.or_if(|max| self.running.len() < max) {
+            match self.queue.pop_front() {
+                Some(mut command) => self.running.push(command.spawn()?),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collapses repeated identical error messages: the first is logged at once,
+/// and its repeats within `window` are counted and summarized once it expires.
+pub struct LogThrottle {
+    window: Duration,
+    clock: Box<dyn Clock>,
+    seen: HashMap<String, (LocalTime, u32)>,
+}
+
+impl LogThrottle {
+    pub fn new(window: Duration, clock: Box<dyn Clock>) -> LogThrottle {
+        LogThrottle {
+            window,
+            clock,
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn log(&mut self, message: String) {
+        for line in self.throttle(message) {
+            error!("{}", line);
+        }
+    }
+
+    /// Logs the summaries of the windows that have expired, so repeats are
+    /// reported even once the error stops recurring.
+    pub fn flush(&mut self) {
+        for line in self.expire() {
+            error!("{}", line);
+        }
+    }
+
+    /// The lines to log for `message`: the summaries of expired windows, then
+    /// `message` itself unless it repeats within its window.
+    fn throttle(&mut self, message: String) -> Vec<String> {
+        let mut lines = self.expire();
+        match self.seen.get_mut(&message) {
+            Some((_, repeats)) => *repeats += 1,
+            None => {
+                self.seen.insert(message.clone(), (self.clock.now(), 0));
+                lines.push(message);
+            }
+        }
+        lines
+    }
+
+    fn expire(&mut self) -> Vec<String> {
+        let now = self.clock.now();
+        let window = self.window;
+        let mut summaries = Vec::new();
+        self.seen.retain(|message, (since, repeats)| {
+            if now < *since + window {
+                return true;
+            }
+            if *repeats > 0 {
+                summaries.push(format!(
+                    "{} ({} more times in the last {})",
+                    message,
+                    repeats,
+                    format_duration(now.signed_duration_since(*since))
+                ));
+            }
+            false
+        });
+        summaries.sort();
+        summaries
+    }
+}
+
+thread_local! {
+    static LOG_SCOPE: RefCell<Option<(String, &'static str)>> = const { RefCell::new(None) };
+}
+
+/// Tags what this thread logs with an entry and what is being done to it until
+/// dropped, for the `entry` and `action` fields of JSON logs.
+pub struct LogScope {
+    previous: Option<(String, &'static str)>,
+}
+
+impl LogScope {
+    pub fn new(entry: &str, action: &'static str) -> LogScope {
+        let previous = LOG_SCOPE.with(|scope| scope.replace(Some((entry.to_owned(), action))));
+        LogScope { previous }
+    }
+}
+
+impl Drop for LogScope {
+    fn drop(&mut self) {
+        LOG_SCOPE.with(|scope| *scope.borrow_mut() = self.previous.take());
+    }
+}
+
+/// The entry and action of the innermost `LogScope` alive on this thread.
+pub fn log_scope() -> Option<(String, &'static str)> {
+    LOG_SCOPE.with(|scope| scope.borrow().clone())
+}
+
+/// Stores local times as UTC RFC 3339 strings, so a saved time means the same
+/// instant whatever the machine's timezone is when it is read back.
+pub mod utc_time {
+    use chrono::offset::{Local, Utc};
+    use chrono::{DateTime, ParseError, SecondsFormat};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    fn to_utc(time: &DateTime<Local>) -> String {
+        time.with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+    }
+
+    fn from_utc(time: &str) -> Result<DateTime<Local>, ParseError> {
+        DateTime::parse_from_rfc3339(time).map(|time| time.with_timezone(&Local))
+    }
+
+    pub fn serialize<S: Serializer>(
+        time: &DateTime<Local>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&to_utc(time))
+    }
+
+    pub fn deserialize<'a, D: Deserializer<'a>>(
+        deserializer: D,
+    ) -> Result<DateTime<Local>, D::Error> {
+        from_utc(&String::deserialize(deserializer)?).map_err(Error::custom)
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            time: &Option<DateTime<Local>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match time {
+                Some(time) => serializer.serialize_some(&to_utc(time)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'a, D: Deserializer<'a>>(
+            deserializer: D,
+        ) -> Result<Option<DateTime<Local>>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|time| from_utc(&time).map_err(Error::custom))
+                .transpose()
+        }
+    }
+
+    pub mod map {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            times: &HashMap<String, DateTime<Local>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(times.iter().map(|(name, time)| (name, to_utc(time))))
+        }
+
+        pub fn deserialize<'a, D: Deserializer<'a>>(
+            deserializer: D,
+        ) -> Result<HashMap<String, DateTime<Local>>, D::Error> {
+            HashMap::<String, String>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(name, time)| Ok((name, from_utc(&time).map_err(Error::custom)?)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use chrono::{DateTime, Local, TimeZone};
+    use std::env;
+    use std::rc::Rc;
+
+    /// A path in the temp directory, unique to this test process and `name`.
+    pub fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("senklot-test-{}-{}", process::id(), name))
+    }
+
+    fn mode_of(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn write_private_creates_an_owner_only_file() {
+        let path = temp_path("write-private-new");
+        write_private(&path, b"state").unwrap();
+
+        assert_eq!(mode_of(&path), 0o600);
+        assert_eq!(fs::read(&path).unwrap(), b"state");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_private_tightens_an_existing_file() {
+        let path = temp_path("write-private-existing");
+        fs::write(&path, "old state").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"state").unwrap();
+
+        assert_eq!(mode_of(&path), 0o600);
+        assert_eq!(fs::read(&path).unwrap(), b"state");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_file_leaves_a_changed_file_alone() {
+        let (path, tmp) = (
+            temp_path("replace-changed"),
+            temp_path("replace-changed.tmp"),
+        );
+        fs::write(&path, "edited").unwrap();
+
+        assert!(!replace_file(&path, &tmp, b"new", Some(b"old")).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"edited");
+        assert!(!tmp.exists());
+
+        assert!(replace_file(&path, &tmp, b"new", Some(b"edited")).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_file_keeps_the_original_when_the_rename_fails() {
+        // Renaming a file over a non-empty directory fails after `tmp` is written.
+        let (path, tmp) = (temp_path("replace-fails"), temp_path("replace-fails.tmp"));
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("hosts"), "original").unwrap();
+
+        assert!(replace_file(&path, &tmp, b"new", None).is_err());
+        assert_eq!(fs::read(path.join("hosts")).unwrap(), b"original");
+        assert!(!tmp.exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_file_keeps_the_mode() {
+        let (path, tmp) = (temp_path("replace-mode"), temp_path("replace-mode.tmp"));
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        assert!(replace_file(&path, &tmp, b"new", None).unwrap());
+        assert_eq!(mode_of(&path), 0o640);
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn sleep(secs: &str) -> process::Command {
+        let mut command = process::Command::new("sleep");
+        command.arg(secs);
+        command
+    }
+
+    #[test]
+    fn hook_pool_respects_its_cap() {
+        let mut pool = HookPool::default();
+        pool.set_max(Some(2));
+        for _ in 0..3 {
+            pool.spawn(sleep("0.2")).unwrap();
+        }
+        assert_eq!(pool.running.len(), 2);
+        assert_eq!(pool.queue.len(), 1);
+
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        pool.poll().unwrap();
+        assert_eq!(pool.running.len(), 1);
+        assert!(pool.queue.is_empty());
+    }
+
+    #[test]
+    fn hook_pool_is_unbounded_by_default() {
+        let mut pool = HookPool::default();
+        for _ in 0..3 {
+            pool.spawn(sleep("0")).unwrap();
+        }
+        assert!(pool.queue.is_empty());
+    }
+
+    #[test]
+    fn unified_diff_of_same_lines_is_empty() {
+        assert_eq!(unified_diff("a", "b", "x\ny\n", "x\ny"), "");
+    }
+
+    #[test]
+    fn unified_diff_shows_changed_lines_with_context() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified_diff("old", "new", old, new),
+            "--- old\n+++ new\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+    }
+
+    #[test]
+    fn unified_diff_of_large_change_is_one_hunk() {
+        let old: String = (0..5000).map(|i| format!("old {}\n", i)).collect();
+        let new: String = (0..5000).map(|i| format!("new {}\n", i)).collect();
+        let old = format!("head\n{}tail\n", old);
+        let new = format!("head\n{}tail\n", new);
+        let diff = unified_diff("old", "new", &old, &new);
+        assert_eq!(diff.matches("@@ ").count(), 1);
+        assert!(diff.contains("@@ -1,5002 +1,5002 @@\n head\n-old 0\n"));
+        assert!(diff.ends_with("+new 4999\n tail\n"));
+    }
+
+    #[test]
+    fn bind_replaces_a_leftover_file() {
+        let path = temp_path("leftover.sock");
+        fs::write(&path, "").unwrap();
+
+        let (socket, _listener) = SocketPath::bind(&path).unwrap();
+        assert!(net::UnixStream::connect(&path).is_ok());
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_refuses_to_replace_a_running_daemon() {
+        let path = temp_path("running.sock");
+        let (_socket, _listener) = SocketPath::bind(&path).unwrap();
+
+        let e = SocketPath::bind(&path).err().unwrap();
+        assert!(e.to_string().starts_with("Another senklot daemon"));
+        assert!(net::UnixStream::connect(&path).is_ok());
+    }
+
+    #[test]
+    fn bind_gives_up_with_the_cause() {
+        let path = temp_path("missing-dir").join("senklot.sock");
+        let e = SocketPath::bind(&path).err().unwrap();
+        assert!(e.to_string().starts_with("Unable to bind the socket"));
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Times {
+        #[serde(with = "utc_time")]
+        at: DateTime<Local>,
+        #[serde(with = "utc_time::option")]
+        until: Option<DateTime<Local>>,
+        #[serde(with = "utc_time::map")]
+        last: HashMap<String, DateTime<Local>>,
+    }
+
+    #[test]
+    fn times_are_stored_as_utc() {
+        use chrono::{FixedOffset, Utc};
+
+        let at = Utc.ymd(2020, 6, 1).and_hms(3, 0, 0).with_timezone(&Local);
+        let times = Times {
+            at,
+            until: None,
+            last: vec![("sns".to_owned(), at)].into_iter().collect(),
+        };
+        let json = serde_json::to_string(&times).unwrap();
+        assert_eq!(
+            json,
+            r#"{"at":"2020-06-01T03:00:00Z","until":null,"last":{"sns":"2020-06-01T03:00:00Z"}}"#
+        );
+
+        // Written with another offset, the same instant is read back.
+        let json = r#"{"at":"2020-06-01T12:00:00+09:00","until":"2020-06-01T03:00:00Z","last":{}}"#;
+        let times: Times = serde_json::from_str(json).unwrap();
+        assert_eq!(times.at, at);
+        assert_eq!(times.until, Some(at));
+        assert_eq!(
+            times
+                .at
+                .with_timezone(&FixedOffset::east(9 * 3600))
+                .to_rfc3339(),
+            "2020-06-01T12:00:00+09:00"
+        );
+    }
+
+    #[test]
+    fn repeated_errors_are_summarized_once_their_window_expires() {
+        let clock = Rc::new(FixedClock::new(Local.ymd(2020, 6, 1).and_hms(12, 0, 0)));
+        let mut throttle = LogThrottle::new(Duration::minutes(10), Box::new(clock.clone()));
+
+        assert_eq!(throttle.throttle("failed".to_owned()), ["failed"]);
+        for _ in 0..3 {
+            clock.advance(Duration::minutes(1));
+            assert!(throttle.throttle("failed".to_owned()).is_empty());
+        }
+        assert_eq!(throttle.throttle("other".to_owned()), ["other"]);
+
+        clock.advance(Duration::minutes(6));
+        assert!(throttle.expire().is_empty());
+        clock.advance(Duration::minutes(1));
+        assert_eq!(throttle.expire(), ["failed (3 more times in the last 10m)"]);
+        assert!(throttle.expire().is_empty());
+
+        clock.advance(Duration::minutes(3));
+        assert_eq!(throttle.throttle("failed".to_owned()), ["failed"]);
+    }
+}